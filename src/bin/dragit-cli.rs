@@ -0,0 +1,19 @@
+use std::{env, process};
+
+use env_logger::Env;
+
+use dragit::cli;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // Daemon reports its decisions through the log, client commands only print results
+    let level = match args.first() {
+        Some(command) if command == "daemon" => "info",
+        _ => "warn",
+    };
+    let env = Env::default().filter_or("LOG_LEVEL", level);
+    env_logger::init_from_env(env);
+
+    process::exit(cli::run(args));
+}
@@ -0,0 +1,189 @@
+/// Receiving side of the headless client. Runs the swarm without any GUI
+/// and answers incoming transfers according to the configured policy,
+/// so it can be kept running as a (systemd) service.
+use libp2p::PeerId;
+
+use crate::cli::{Client, EXIT_ERROR};
+use crate::p2p::{PeerEvent, RejectReason, Rejection, TransferCommand};
+use crate::user_data::{AcceptPolicy, CollisionPolicy, UserConfig};
+
+pub fn is_accepted(policy: AcceptPolicy, accepted_peers: &[String], sender: &PeerId) -> bool {
+    match policy {
+        AcceptPolicy::All => true,
+        AcceptPolicy::Listed => {
+            let sender = sender.to_base58();
+            accepted_peers.iter().any(|peer| *peer == sender)
+        }
+        AcceptPolicy::Reject => false,
+    }
+}
+
+/// Returns the reason sent back to the sender if the payload is not accepted
+pub fn check_incoming(
+    policy: AcceptPolicy,
+    accepted_peers: &[String],
+    max_size: Option<u64>,
+    sender: &PeerId,
+    size: usize,
+) -> Result<(), RejectReason> {
+    if !is_accepted(policy, accepted_peers, sender) {
+        return match policy {
+            AcceptPolicy::Listed => Err(RejectReason::NotTrusted),
+            _ => Err(RejectReason::Declined),
+        };
+    }
+    match max_size {
+        Some(max_size) if size as u64 > max_size => Err(RejectReason::TooLarge),
+        _ => Ok(()),
+    }
+}
+
+/// Runs until the p2p server stops. Returns the process exit code.
+pub fn run_daemon(policy: Option<AcceptPolicy>, peers: Vec<String>) -> i32 {
+    let config = match UserConfig::new() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Could not load the config: {:?}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let policy = policy.unwrap_or(config.get_accept_policy());
+    let mut accepted_peers = config.get_accepted_peers();
+    accepted_peers.extend(peers);
+    // Devices paired through the GUI are accepted as well
+    accepted_peers.extend(config.get_trusted_peers());
+    let max_size = config.get_accept_max_size();
+
+    info!(
+        "Starting daemon, policy: {:?}, downloads directory: {:?}",
+        policy,
+        config.get_downloads_dir()
+    );
+    if policy == AcceptPolicy::Listed && accepted_peers.is_empty() {
+        warn!("No accepted or paired peers, every transfer will be rejected");
+    }
+
+    let client = Client::start(false);
+    loop {
+        match client.next_event(None) {
+            Some(PeerEvent::FileIncoming(id, name, _, size, transfer_type, _, sender, _)) => {
+                let checked =
+                    check_incoming(policy, &accepted_peers, max_size, &sender.peer_id, size);
+                let command = match checked {
+                    Ok(()) => {
+                        info!(
+                            "Accepting {} {:?} {} ({} bytes) from {} ({})",
+                            id,
+                            transfer_type,
+                            name,
+                            size,
+                            sender.display_name(),
+                            sender.peer_id
+                        );
+                        TransferCommand::Accept(id)
+                    }
+                    Err(reason) => {
+                        info!(
+                            "Rejecting {} {:?} {} ({} bytes) from {} ({}): {}",
+                            id,
+                            transfer_type,
+                            name,
+                            size,
+                            sender.display_name(),
+                            sender.peer_id,
+                            reason
+                        );
+                        TransferCommand::Deny(id, Rejection::new(reason))
+                    }
+                };
+                client.answer(command);
+            }
+            // Nobody can answer the question, so the payload is kept under a new name
+            Some(PeerEvent::FileExists(id, name)) => {
+                info!("{} already exists, keeping both", name);
+                client.answer(TransferCommand::Resolve(id, CollisionPolicy::Rename));
+            }
+            Some(PeerEvent::FileSkipped(name)) => info!("Skipped {}, it already exists", name),
+            Some(PeerEvent::FileCorrect(name, payload)) => {
+                info!("Received {}: {}", name, payload);
+            }
+            Some(PeerEvent::FileIncorrect(files)) => {
+                warn!("Received files are incorrect: {}", files.join(", "))
+            }
+            Some(PeerEvent::TransferFailed(id, e)) => error!("Transfer {} failed: {}", id, e),
+            Some(PeerEvent::TransferCancelledByPeer(id)) => {
+                warn!("Transfer {} cancelled by the sender", id)
+            }
+            Some(PeerEvent::IncompatibleVersion(peer)) => warn!("{}", peer.incompatible_hint()),
+            Some(PeerEvent::PeersUpdated(peers)) => {
+                debug!("Known peers: {}", peers.len());
+            }
+            Some(PeerEvent::Error(e)) => error!("Encountered an error: {}", e),
+            Some(_) => (),
+            None => {
+                error!("Server stopped");
+                return EXIT_ERROR;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::daemon::{check_incoming, is_accepted};
+    use crate::p2p::RejectReason;
+    use crate::user_data::AcceptPolicy;
+    use libp2p::PeerId;
+
+    #[test]
+    fn test_accept_all() {
+        let sender = PeerId::random();
+
+        assert!(is_accepted(AcceptPolicy::All, &[], &sender));
+    }
+
+    #[test]
+    fn test_reject_all() {
+        let sender = PeerId::random();
+        let peers = vec![sender.to_base58()];
+
+        assert!(!is_accepted(AcceptPolicy::Reject, &peers, &sender));
+    }
+
+    #[test]
+    fn test_accept_listed() {
+        let sender = PeerId::random();
+        let peers = vec![PeerId::random().to_base58(), sender.to_base58()];
+
+        assert!(is_accepted(AcceptPolicy::Listed, &peers, &sender));
+    }
+
+    #[test]
+    fn test_reject_not_listed() {
+        let sender = PeerId::random();
+        let peers = vec![PeerId::random().to_base58()];
+
+        assert!(!is_accepted(AcceptPolicy::Listed, &peers, &sender));
+    }
+
+    #[test]
+    fn test_reject_reasons() {
+        let sender = PeerId::random();
+        let peers = vec![PeerId::random().to_base58()];
+
+        let checked = check_incoming(AcceptPolicy::Listed, &peers, None, &sender, 10);
+        assert_eq!(checked, Err(RejectReason::NotTrusted));
+        let checked = check_incoming(AcceptPolicy::Reject, &peers, None, &sender, 10);
+        assert_eq!(checked, Err(RejectReason::Declined));
+    }
+
+    #[test]
+    fn test_reject_too_large() {
+        let sender = PeerId::random();
+
+        let checked = check_incoming(AcceptPolicy::All, &[], Some(10), &sender, 11);
+        assert_eq!(checked, Err(RejectReason::TooLarge));
+        let checked = check_incoming(AcceptPolicy::All, &[], Some(10), &sender, 10);
+        assert_eq!(checked, Ok(()));
+    }
+}
@@ -0,0 +1,487 @@
+/// Headless frontend for Dragit, useful on machines without a display
+/// (build boxes, SSH sessions). It drives the same p2p server as the GTK
+/// window, but consumes the peer events from the terminal.
+use std::error::Error;
+use std::io::{self, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use async_std::channel::{bounded, Receiver, Sender};
+use async_std::{future, task};
+use libp2p::PeerId;
+
+use crate::p2p::peer::Direction;
+use crate::p2p::{
+    run_server, CurrentPeers, FileToSend, OperatingSystem, Payload, Peer, PeerEvent, RejectReason,
+    Rejection, TransferCommand, TransferId, TrustedPeers,
+};
+use crate::user_data::{AcceptPolicy, UserConfig};
+
+mod daemon;
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_REJECTED: i32 = 3;
+pub const EXIT_INCORRECT: i32 = 4;
+pub const EXIT_PEER_NOT_FOUND: i32 = 5;
+pub const EXIT_CANCELLED: i32 = 6;
+pub const EXIT_INCOMPATIBLE: i32 = 7;
+
+const DEFAULT_DISCOVERY_TIMEOUT: u64 = 10;
+
+const USAGE: &str = "Usage:
+    dragit-cli peers [--timeout <seconds>]
+    dragit-cli send <path|-> --to <hostname|peer-id> [--timeout <seconds>]
+    dragit-cli daemon [--accept <all|listed|reject>] [--peer <peer-id>]...
+    dragit-cli identity [--regenerate]
+
+Commands:
+    peers     List devices discovered in the local network
+    send      Send a file, directory or standard input (-) to the device
+    daemon    Receive files without GUI, answering according to the policy
+    identity  Print peer id of this device
+
+Options:
+    --timeout <seconds>    How long to wait for the devices to be discovered
+    --accept <policy>      Override accept_policy from the config file
+    --peer <peer-id>       Accept transfers from this peer (with listed policy)
+    --regenerate           Replace the stored keypair, which changes the peer id";
+
+pub struct Client {
+    file_sender: Sender<FileToSend>,
+    peer_receiver: Receiver<PeerEvent>,
+    command_sender: Sender<TransferCommand>,
+    deny_incoming: bool,
+}
+
+impl Client {
+    /// Starts the p2p server in a separate thread. If `deny_incoming` is set,
+    /// the client rejects incoming transfers on its own.
+    pub fn start(deny_incoming: bool) -> Client {
+        let (file_sender, file_receiver) = bounded::<FileToSend>(1024 * 24);
+        let (peer_sender, peer_receiver) = bounded::<PeerEvent>(1024 * 24);
+        let (command_sender, command_receiver) = bounded::<TransferCommand>(1024 * 24);
+
+        // The p2p server is the same one the GTK window uses
+        let sender_clone = peer_sender.clone();
+        let trusted = TrustedPeers::from_config();
+        thread::spawn(move || {
+            match run_server(peer_sender, file_receiver, command_receiver, trusted) {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Server error: {:?}", e);
+                    let _ = sender_clone.try_send(PeerEvent::Error(e.to_string()));
+                }
+            }
+        });
+
+        Client {
+            file_sender,
+            peer_receiver,
+            command_sender,
+            deny_incoming,
+        }
+    }
+
+    pub fn answer(&self, command: TransferCommand) {
+        if let Err(e) = self.command_sender.try_send(command) {
+            error!("Could not send the answer: {:?}", e);
+        }
+    }
+
+    /// Waits for the next event which is not handled by the client itself.
+    /// Returns None if nothing arrived before the timeout (if any).
+    pub fn next_event(&self, timeout: Option<Duration>) -> Option<PeerEvent> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            let received = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.checked_duration_since(Instant::now())?;
+                    match task::block_on(future::timeout(remaining, self.peer_receiver.recv())) {
+                        Ok(received) => received,
+                        Err(_) => return None,
+                    }
+                }
+                None => task::block_on(self.peer_receiver.recv()),
+            };
+            match received {
+                Ok(PeerEvent::FileIncoming(id, name, _, _, _, _, _, _)) if self.deny_incoming => {
+                    // There is nobody to answer the question in headless client
+                    info!("Denying incoming {}, client only sends files", name);
+                    let rejection = Rejection::new(RejectReason::Declined);
+                    self.answer(TransferCommand::Deny(id, rejection));
+                }
+                Ok(event) => return Some(event),
+                Err(e) => {
+                    error!("Receiver error: {:?}", e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Collects peer updates for the given time and returns the latest known list
+    pub fn wait_for_peers(&self, timeout: Duration) -> Result<CurrentPeers, String> {
+        let deadline = Instant::now() + timeout;
+        let mut peers: CurrentPeers = vec![];
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match self.next_event(Some(remaining)) {
+                Some(PeerEvent::PeersUpdated(list)) => peers = list,
+                Some(PeerEvent::Error(e)) => return Err(e),
+                Some(_) => (),
+                None => break,
+            }
+        }
+        Ok(peers)
+    }
+
+    /// Waits until the peer identified by hostname or peer id shows up
+    pub fn find_peer(&self, target: &str, timeout: Duration) -> Result<Option<Peer>, String> {
+        let deadline = Instant::now() + timeout;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match self.next_event(Some(remaining)) {
+                Some(PeerEvent::PeersUpdated(list)) => {
+                    if let Some(peer) = list.into_iter().find(|p| peer_matches(p, target)) {
+                        return Ok(Some(peer));
+                    }
+                }
+                Some(PeerEvent::Error(e)) => return Err(e),
+                Some(_) => (),
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Pushes the file to the server and follows the transfer until it's finished,
+    /// which is once the other device confirmed the payload or closed the stream.
+    /// Returns the process exit code.
+    pub fn send(&self, file: FileToSend) -> i32 {
+        let target = file.peer.clone();
+        if let Err(e) = self.file_sender.try_send(file) {
+            eprintln!("Could not start the transfer: {}", e);
+            return EXIT_ERROR;
+        }
+
+        // Events of any other transfer don't tell anything about this one
+        let mut transfer: Option<TransferId> = None;
+        loop {
+            // Transfer can take any amount of time, the swarm reports its own errors
+            let event = match self.next_event(None) {
+                Some(event) => event,
+                None => return EXIT_ERROR,
+            };
+            if let PeerEvent::TransferStarted(id, _, peer, Direction::Outgoing) = &event {
+                if transfer.is_none() && peer.peer_id == target {
+                    transfer = Some(*id);
+                }
+            }
+            if event.transfer_id().map_or(false, |id| Some(id) != transfer) {
+                continue;
+            }
+            match event {
+                PeerEvent::TransferPreparing((_, counter, total)) => {
+                    eprintln!("Preparing {:.0}%", percent(counter, total));
+                }
+                PeerEvent::WaitingForAnswer(_) => {
+                    eprintln!("Waiting for answer from the other device...");
+                }
+                PeerEvent::TransferProgress((_, progress, _)) => {
+                    eprintln!("Sent {:.0}%: {}", progress.fraction() * 100.0, progress);
+                }
+                PeerEvent::TransferCompleted(_) => {
+                    eprintln!("Transfer completed");
+                    return EXIT_OK;
+                }
+                PeerEvent::TransferRejected(_, rejection) => {
+                    eprintln!("{}", rejection);
+                    return EXIT_REJECTED;
+                }
+                PeerEvent::TransferFailed(_, e) => {
+                    eprintln!("{}", e);
+                    return EXIT_ERROR;
+                }
+                PeerEvent::TransferCancelledByPeer(_) => {
+                    eprintln!("Transfer cancelled by the other device");
+                    return EXIT_CANCELLED;
+                }
+                PeerEvent::TransferCorrupted(_) => {
+                    eprintln!("File is incorrect, the other device received it corrupted");
+                    return EXIT_INCORRECT;
+                }
+                PeerEvent::IncompatibleVersion(peer) if peer.peer_id == target => {
+                    eprintln!("{}", peer.incompatible_hint());
+                    return EXIT_INCOMPATIBLE;
+                }
+                // Discovery errors don't stop the transfer, the stopped server closes the channel
+                PeerEvent::Error(e) => eprintln!("Encountered an error: {}", e),
+                _ => (),
+            }
+        }
+    }
+}
+
+fn percent(counter: usize, total: usize) -> f64 {
+    if total > 0 {
+        (counter as f64 / total as f64 * 100.0).min(100.0)
+    } else {
+        100.0
+    }
+}
+
+fn peer_matches(peer: &Peer, target: &str) -> bool {
+    peer.peer_id.to_base58() == target || (peer.hostname == target && !is_unknown(peer))
+}
+
+fn is_unknown(peer: &Peer) -> bool {
+    peer.os == OperatingSystem::Unknown
+}
+
+fn format_peer(peer: &Peer) -> String {
+    format!(
+        "{}\t{}\t{:?}\t{}",
+        peer.peer_id.to_base58(),
+        peer.hostname,
+        peer.os,
+        peer.address
+    )
+}
+
+fn read_payload(path: &str) -> Result<Payload, Box<dyn Error>> {
+    if path == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        Ok(Payload::Text(text))
+    } else {
+        Ok(Payload::new_for_path(path.to_string())?)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Peers {
+        timeout: Duration,
+    },
+    Send {
+        path: String,
+        to: String,
+        timeout: Duration,
+    },
+    Daemon {
+        policy: Option<AcceptPolicy>,
+        peers: Vec<String>,
+    },
+    Identity {
+        regenerate: bool,
+    },
+}
+
+fn parse_policy(value: &str) -> Result<AcceptPolicy, String> {
+    match value {
+        "all" => Ok(AcceptPolicy::All),
+        "listed" => Ok(AcceptPolicy::Listed),
+        "reject" => Ok(AcceptPolicy::Reject),
+        other => Err(format!("Invalid accept policy: {}", other)),
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut positional: Vec<&String> = vec![];
+    let mut to: Option<String> = None;
+    let mut timeout = Duration::from_secs(DEFAULT_DISCOVERY_TIMEOUT);
+    let mut policy: Option<AcceptPolicy> = None;
+    let mut peers: Vec<String> = vec![];
+    let mut regenerate = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--to" => {
+                let value = iter.next().ok_or("Missing value for --to")?;
+                to = Some(value.to_string());
+            }
+            "--timeout" => {
+                let value = iter.next().ok_or("Missing value for --timeout")?;
+                let seconds = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid timeout: {}", value))?;
+                timeout = Duration::from_secs(seconds);
+            }
+            "--accept" => {
+                let value = iter.next().ok_or("Missing value for --accept")?;
+                policy = Some(parse_policy(value)?);
+            }
+            "--peer" => {
+                let value = iter.next().ok_or("Missing value for --peer")?;
+                peers.push(value.to_string());
+            }
+            "--regenerate" => regenerate = true,
+            "-" => positional.push(arg),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    match positional.as_slice() {
+        [command] if command.as_str() == "peers" => Ok(Command::Peers { timeout }),
+        [command, path] if command.as_str() == "send" => match to {
+            Some(to) => Ok(Command::Send {
+                path: path.to_string(),
+                to,
+                timeout,
+            }),
+            None => Err("Missing --to <hostname|peer-id>".to_string()),
+        },
+        [command] if command.as_str() == "daemon" => Ok(Command::Daemon { policy, peers }),
+        [command] if command.as_str() == "identity" => Ok(Command::Identity { regenerate }),
+        [] => Err("Missing command".to_string()),
+        _ => Err("Invalid arguments".to_string()),
+    }
+}
+
+pub fn run(args: Vec<String>) -> i32 {
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    match command {
+        Command::Peers { timeout } => {
+            let client = Client::start(true);
+            match client.wait_for_peers(timeout) {
+                Ok(peers) => {
+                    for peer in peers.iter() {
+                        println!("{}", format_peer(peer));
+                    }
+                    EXIT_OK
+                }
+                Err(e) => {
+                    eprintln!("Encountered an error: {}", e);
+                    EXIT_ERROR
+                }
+            }
+        }
+        Command::Send { path, to, timeout } => {
+            let payload = match read_payload(&path) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    eprintln!("Could not read {}: {}", path, e);
+                    return EXIT_ERROR;
+                }
+            };
+            let client = Client::start(true);
+            let peer = match client.find_peer(&to, timeout) {
+                Ok(Some(peer)) => peer,
+                Ok(None) => {
+                    eprintln!("Device {} not found", to);
+                    return EXIT_PEER_NOT_FOUND;
+                }
+                Err(e) => {
+                    eprintln!("Encountered an error: {}", e);
+                    return EXIT_ERROR;
+                }
+            };
+            match FileToSend::new(&peer.peer_id, payload) {
+                Ok(file) => client.send(file),
+                Err(e) => {
+                    eprintln!("Could not prepare {}: {}", path, e);
+                    EXIT_ERROR
+                }
+            }
+        }
+        Command::Daemon { policy, peers } => daemon::run_daemon(policy, peers),
+        Command::Identity { regenerate } => {
+            let keypair = UserConfig::new().and_then(|config| {
+                if regenerate {
+                    config.regenerate_keypair()
+                } else {
+                    config.get_keypair()
+                }
+            });
+            match keypair {
+                Ok(keypair) => {
+                    println!("{}", PeerId::from(keypair.public()));
+                    EXIT_OK
+                }
+                Err(e) => {
+                    eprintln!("Could not load the identity: {}", e);
+                    EXIT_ERROR
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{parse_args, Command};
+    use crate::user_data::{AcceptPolicy, UserConfig};
+    use std::time::Duration;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_peers() {
+        let result = parse_args(&args(&["peers", "--timeout", "3"])).unwrap();
+
+        assert_eq!(
+            result,
+            Command::Peers {
+                timeout: Duration::from_secs(3)
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_send_stdin() {
+        let result = parse_args(&args(&["send", "-", "--to", "laptop"])).unwrap();
+
+        assert_eq!(
+            result,
+            Command::Send {
+                path: "-".to_string(),
+                to: "laptop".to_string(),
+                timeout: Duration::from_secs(10),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_daemon() {
+        let result = parse_args(&args(&[
+            "daemon", "--accept", "listed", "--peer", "peer-a", "--peer", "peer-b",
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            result,
+            Command::Daemon {
+                policy: Some(AcceptPolicy::Listed),
+                peers: vec!["peer-a".to_string(), "peer-b".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_daemon_invalid_policy() {
+        assert!(parse_args(&args(&["daemon", "--accept", "maybe"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_identity_regenerate() {
+        let result = parse_args(&args(&["identity", "--regenerate"])).unwrap();
+
+        assert_eq!(result, Command::Identity { regenerate: true });
+    }
+
+    #[test]
+    fn test_parse_send_without_target() {
+        assert!(parse_args(&args(&["send", "file.txt"])).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 use std::error::Error;
+use std::fs;
 use std::io;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use async_std::channel::Sender;
@@ -14,7 +16,9 @@ use gtk::{DestDefaults, Label, TargetEntry, TargetFlags};
 
 use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
 
-use crate::p2p::{FileToSend, OperatingSystem, Payload, Peer, PeerEvent};
+use crate::dnd::dialogs::PairingDialog;
+use crate::p2p::pairing::verification_code;
+use crate::p2p::{FileToSend, OperatingSystem, Payload, Peer, PeerEvent, TrustedPeers};
 use crate::user_data::UserConfig;
 
 pub const STYLE: &str = "
@@ -144,6 +148,13 @@ impl MainLayout {
                 label.set_selectable(true);
                 recent_item.pack_start(&label, false, false, 0);
             }
+            Payload::Batch(paths) => {
+                let link = get_batch_link(file_name, &paths);
+                let image =
+                    gtk::Image::from_icon_name(Some("folder-documents"), gtk::IconSize::Dialog);
+                recent_item.pack_start(&image, false, false, 0);
+                recent_item.pack_start(&link, false, false, 0);
+            }
         }
 
         self.recent_layout.attach_next_to(
@@ -224,11 +235,19 @@ impl MainLayout {
 pub struct PeerItem {
     pub container: gtk::ListBoxRow,
     pub label: Label,
+    pairing_label: Label,
+    pair_button: gtk::Button,
 }
 
 impl PeerItem {
     // TODO: is this safe to use &str here?
-    pub fn new(name: &str, address: &Multiaddr, hostname: &str, os: &OperatingSystem) -> PeerItem {
+    pub fn new(
+        name: &str,
+        address: &Multiaddr,
+        hostname: &str,
+        os: &OperatingSystem,
+        paired: bool,
+    ) -> PeerItem {
         let ip = PeerItem::extract_ip(&address);
         let display_name = format!(
             concat!(
@@ -247,6 +266,11 @@ impl PeerItem {
 
         let image = gtk::Image::from_icon_name(Some("insert-object"), gtk::IconSize::Dialog);
 
+        let pairing_label = Label::new(None);
+        let pair_button = gtk::Button::with_label("Pair");
+        pair_button.set_halign(gtk::Align::Center);
+        Self::show_paired(&pairing_label, &pair_button, paired);
+
         let container = gtk::ListBoxRow::new();
         container.set_widget_name(name);
         container.set_vexpand(true);
@@ -256,9 +280,52 @@ impl PeerItem {
 
         inner_container.pack_start(&image, true, true, 0);
         inner_container.pack_start(&label, true, true, 0);
+        inner_container.pack_start(&pairing_label, false, false, 0);
+        inner_container.pack_start(&pair_button, false, false, 10);
         container.add(&inner_container);
 
-        PeerItem { container, label }
+        PeerItem {
+            container,
+            label,
+            pairing_label,
+            pair_button,
+        }
+    }
+
+    fn show_paired(pairing_label: &Label, pair_button: &gtk::Button, paired: bool) {
+        if paired {
+            pairing_label.set_markup("<big><b>Paired</b>: Yes</big>");
+            pair_button.set_label("Paired");
+            pair_button.set_sensitive(false);
+        } else {
+            pairing_label.set_markup("<big><b>Paired</b>: No</big>");
+            pair_button.set_label("Pair");
+            pair_button.set_sensitive(true);
+        }
+    }
+
+    /// Pairing marks the device as trusted once user confirms that
+    /// both devices show the same verification code.
+    pub fn bind_pairing(
+        self,
+        peer: &Peer,
+        window: &gtk::ApplicationWindow,
+        trusted: TrustedPeers,
+    ) -> Self {
+        let remote = peer.peer_id.clone();
+        let hostname = peer.hostname.clone();
+        let window_weak = window.downgrade();
+        let pairing_label = self.pairing_label.clone();
+
+        self.pair_button.connect_clicked(move |button| {
+            if let Some(win) = window_weak.upgrade() {
+                match pair_with_peer(&win, &remote, &hostname, &trusted) {
+                    Ok(paired) => Self::show_paired(&pairing_label, button, paired),
+                    Err(e) => error!("Pairing failed: {:?}", e),
+                }
+            }
+        });
+        self
     }
 
     fn extract_ip(address: &Multiaddr) -> String {
@@ -289,9 +356,11 @@ impl PeerItem {
 
         self.container.connect_drag_data_received(
             move |_win, _drag_context, _, _, selection_data, _, _| {
-                let file_to_send = match selection_data.uris().pop() {
-                    Some(file) => Self::get_file_payload(&peer_id, file.to_string()),
-                    None => Self::get_text_payload(&selection_data, &peer_id),
+                let uris = selection_data.uris();
+                let file_to_send = match uris.len() {
+                    0 => Self::get_text_payload(&selection_data, &peer_id),
+                    1 => Self::get_file_payload(&peer_id, uris[0].to_string()),
+                    _ => Self::get_batch_payload(&peer_id, &uris),
                 };
 
                 match file_to_send {
@@ -313,26 +382,41 @@ impl PeerItem {
     }
 
     fn get_file_payload(peer_id: &PeerId, file: String) -> Result<FileToSend, Box<dyn Error>> {
-        let file = gio::File::for_uri(&file);
+        let payload = Payload::new_for_path(Self::get_file_path(&file))?;
+        Ok(FileToSend::new(peer_id, payload)?)
+    }
+
+    /// Many files and directories dropped at once are sent as one transfer
+    fn get_batch_payload(
+        peer_id: &PeerId,
+        uris: &[glib::GString],
+    ) -> Result<FileToSend, Box<dyn Error>> {
+        let paths = uris
+            .iter()
+            .map(|uri| {
+                // Fail early if any of the items is gone, like for single files
+                let path = Self::get_file_path(uri);
+                fs::metadata(&path)?;
+                Ok(path)
+            })
+            .collect::<Result<Vec<String>, io::Error>>()?;
+        info!("Dropped {} items", paths.len());
+        Ok(FileToSend::new(peer_id, Payload::Batch(paths))?)
+    }
+
+    fn get_file_path(uri: &str) -> String {
+        let file = gio::File::for_uri(uri);
         if file.is_native() {
             match file.path() {
-                Some(p) => {
-                    let path = clean_file_proto(&p.display().to_string());
-                    let payload = Payload::new_for_path(path)?;
-                    Ok(FileToSend::new(peer_id, payload)?)
-                }
+                Some(p) => clean_file_proto(&p.display().to_string()),
                 None => {
                     let uri: String = file.uri().into();
-                    let path = clean_file_proto(&uri);
-                    let payload = Payload::new_for_path(path)?;
-                    Ok(FileToSend::new(peer_id, payload)?)
+                    clean_file_proto(&uri)
                 }
             }
         } else {
             let uri: String = file.uri().into();
-            let path = clean_file_proto(&uri);
-            let payload = Payload::new_for_path(path)?;
-            Ok(FileToSend::new(peer_id, payload)?)
+            clean_file_proto(&uri)
         }
     }
 
@@ -348,6 +432,31 @@ impl PeerItem {
     }
 }
 
+fn pair_with_peer(
+    window: &gtk::ApplicationWindow,
+    remote: &PeerId,
+    hostname: &str,
+    trusted: &TrustedPeers,
+) -> Result<bool, Box<dyn Error>> {
+    let mut config = UserConfig::new()?;
+    let local = PeerId::from(config.get_keypair()?.public());
+    let code = verification_code(&local, remote);
+
+    let dialog = PairingDialog::new(window, hostname, &code);
+    match dialog.run() {
+        gtk::ResponseType::Yes => {
+            info!("Paired with {}", remote);
+            config.add_trusted_peer(remote)?;
+            trusted.insert(remote.to_owned());
+            Ok(true)
+        }
+        _ => {
+            info!("Pairing with {} cancelled", remote);
+            Ok(false)
+        }
+    }
+}
+
 #[cfg(not(target_os = "windows"))]
 fn clean_file_proto(value: &str) -> String {
     value.replace("file://", "")
@@ -434,3 +543,13 @@ pub fn get_link(file_name: &str, path: &str) -> gtk::LinkButton {
 pub fn get_link(file_name: &str, path: &str) -> gtk::LinkButton {
     gtk::LinkButton::with_label(&path, file_name)
 }
+
+/// Batch items are unpacked next to each other, so the link opens their directory
+pub fn get_batch_link(file_name: &str, paths: &[String]) -> gtk::LinkButton {
+    let dir = paths
+        .first()
+        .and_then(|path| Path::new(path).parent())
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or_default();
+    get_link(file_name, &dir)
+}
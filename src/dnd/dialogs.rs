@@ -2,10 +2,12 @@ use gtk::prelude::*;
 
 use bytesize::ByteSize;
 
-use crate::p2p::TransferType;
+use crate::p2p::{Peer, TransferType};
 use crate::user_data::UserConfig;
 
-pub struct AcceptFileDialog(gtk::MessageDialog);
+/// Second field is the note for the sender, typed when declining the payload
+#[derive(Clone)]
+pub struct AcceptFileDialog(gtk::MessageDialog, Option<gtk::Entry>);
 
 impl AcceptFileDialog {
     pub fn new(
@@ -13,22 +15,128 @@ impl AcceptFileDialog {
         name: String,
         size: usize,
         transfer_type: TransferType,
+        count: usize,
+        sender: &Peer,
+        available: Option<u64>,
     ) -> AcceptFileDialog {
         let readable_size = ByteSize(size as u64);
+        let from = format!("from {} ({:?})", sender.display_name(), sender.os);
         let message = match transfer_type {
-            TransferType::File => format!("Incoming file {} ({}).", name, readable_size),
-            TransferType::Text => format!("Incoming text {}.", name),
-            TransferType::Dir => format!("Incoming directory {}.", name),
+            TransferType::File => format!("Incoming file {} ({}) {}.", name, readable_size, from),
+            TransferType::Text => format!("Incoming text {} {}.", name, from),
+            TransferType::Dir => format!("Incoming directory {} {}.", name, from),
+            TransferType::Batch => format!(
+                "Incoming {} files and directories ({}) {}.",
+                count, readable_size, from
+            ),
+        };
+        let message = match available {
+            Some(available) => format!(
+                "{}\nFree space in the downloads directory: {}.",
+                message,
+                ByteSize(available)
+            ),
+            None => message,
+        };
+        let (message, message_type) = if sender.paired {
+            (
+                format!("{}\n\nWould you like to accept?", message),
+                gtk::MessageType::Question,
+            )
+        } else {
+            (
+                format!(
+                    concat!(
+                        "{}\n\n",
+                        "Warning: the sender is not paired with this device.\n",
+                        "Accept only if you expect this payload.\n\n",
+                        "Would you like to accept?"
+                    ),
+                    message
+                ),
+                gtk::MessageType::Warning,
+            )
         };
-        let message = format!("{}\n\nWould you like to accept?", message);
         let dialog = gtk::MessageDialog::new(
             Some(window),
             gtk::DialogFlags::MODAL,
-            gtk::MessageType::Question,
+            message_type,
             gtk::ButtonsType::YesNo,
             &message,
         );
-        AcceptFileDialog(dialog)
+        let note = gtk::Entry::new();
+        note.set_placeholder_text(Some("Optional note for the sender if you decline"));
+        if let Ok(area) = dialog.message_area().downcast::<gtk::Box>() {
+            area.pack_start(&note, false, false, 0);
+        }
+        AcceptFileDialog(dialog, Some(note))
+    }
+
+    /// Asks what to do with the accepted payload, which name is already taken.
+    /// Answers with Yes to keep both, Accept to replace and No to skip.
+    pub fn new_for_collision(window: &gtk::ApplicationWindow, name: &str) -> AcceptFileDialog {
+        let message = format!(
+            "{} already exists in the downloads directory.\n\nWhat would you like to do?",
+            name
+        );
+        let dialog = gtk::MessageDialog::new(
+            Some(window),
+            gtk::DialogFlags::MODAL,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::None,
+            &message,
+        );
+        dialog.add_button("Skip", gtk::ResponseType::No);
+        dialog.add_button("Replace", gtk::ResponseType::Accept);
+        dialog.add_button("Keep both", gtk::ResponseType::Yes);
+        AcceptFileDialog(dialog, None)
+    }
+
+    /// Shows the dialog without blocking the main loop, so it can be closed
+    /// when the sender cancels the transfer before the answer. The note is
+    /// passed along with the response, if the dialog has one. `on_answer` has to
+    /// handle only the first response.
+    pub fn show<F: Fn(gtk::ResponseType, Option<String>) + 'static>(&self, on_answer: F) {
+        let note = self.1.clone();
+        self.0.connect_response(move |dialog, response| {
+            let text = note.as_ref().map(|note| note.text().to_string());
+            on_answer(response, text);
+            // Closing destroys the dialog, hidden ones would pile up as toplevel windows.
+            // It responds once more with DeleteEvent, which the callers ignore.
+            dialog.close();
+        });
+        self.0.show_all();
+    }
+
+    pub fn cancel(&self) {
+        self.0.response(gtk::ResponseType::Cancel);
+    }
+}
+
+pub struct PairingDialog(gtk::MessageDialog);
+
+impl PairingDialog {
+    pub fn new(window: &gtk::ApplicationWindow, hostname: &str, code: &str) -> PairingDialog {
+        let message = format!(
+            concat!(
+                "Pairing with <b>{}</b>\n\n",
+                "Verification code: <big><b>{}</b></big>\n\n",
+                "Click \"Pair\" on the other device as well and compare the codes.\n",
+                "Confirm only if both devices show the same code.\n\n",
+                "Do the codes match?"
+            ),
+            glib::markup_escape_text(hostname),
+            code
+        );
+        let dialog = gtk::MessageDialog::new(
+            Some(window),
+            gtk::DialogFlags::MODAL,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::YesNo,
+            "",
+        );
+        dialog.set_markup(&message);
+        PairingDialog(dialog)
     }
 
     pub fn run(&self) -> gtk::ResponseType {
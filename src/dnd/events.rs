@@ -10,7 +10,7 @@ use glib::{timeout_add_local, Continue};
 use gtk::ApplicationWindow;
 
 use crate::dnd::components::{get_item_name, EmptyListItem, PeerItem};
-use crate::p2p::{CurrentPeers, FileToSend, PeerEvent};
+use crate::p2p::{CurrentPeers, FileToSend, PeerEvent, TrustedPeers};
 
 pub fn pool_peers(
     window: &ApplicationWindow,
@@ -18,6 +18,7 @@ pub fn pool_peers(
     file_sender: Arc<Mutex<Sender<FileToSend>>>,
     peer_receiver: Arc<Mutex<Receiver<PeerEvent>>>,
     peer_event_sender: glib::Sender<PeerEvent>,
+    trusted: TrustedPeers,
 ) {
     // TODO: investigate why set_placeholder() doesn't work
     let empty_item = EmptyListItem::new();
@@ -40,14 +41,21 @@ pub fn pool_peers(
             if children.len() == 0 {
                 empty_item.show();
             }
-            if let Ok(event) = peer_receiver.lock().unwrap().try_recv() {
-                let peers: CurrentPeers = match event {
-                    PeerEvent::PeersUpdated(list) => list,
+            // Running transfers report several times a second, so everything queued since
+            // the last tick is handled. Only the latest list of peers is worth showing.
+            let mut peers: Option<CurrentPeers> = None;
+            let receiver = peer_receiver.lock().unwrap();
+            while let Ok(event) = receiver.try_recv() {
+                match event {
+                    PeerEvent::PeersUpdated(list) => peers = Some(list),
                     event => {
                         let _ = peer_event_sender.send(event);
-                        return Continue(true);
                     }
-                };
+                }
+            }
+            drop(receiver);
+
+            if let Some(peers) = peers {
                 empty_item.hide();
 
                 // Clear the item list before receiving new list of peers from event
@@ -59,14 +67,18 @@ pub fn pool_peers(
                     let hostname = &peer.hostname;
                     let os = &peer.os;
 
-                    let item = PeerItem::new(name, addr, &hostname, &os);
+                    let item = PeerItem::new(name, addr, &hostname, &os, peer.paired);
                     let sender = file_sender.clone();
                     let event_sender = peer_event_sender.clone();
                     let item = item.bind_drag_and_drop(peer, sender, event_sender);
+                    let item = match weak_window.upgrade() {
+                        Some(win) => item.bind_pairing(peer, &win, trusted.clone()),
+                        None => item,
+                    };
 
                     layout_in.add(&item.container);
                 }
-            };
+            }
         }
 
         if let Some(win) = weak_window.upgrade() {
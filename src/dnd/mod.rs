@@ -1,4 +1,7 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::error::Error;
+use std::rc::Rc;
 
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -18,8 +21,11 @@ use async_std::channel::{bounded, Receiver, Sender};
 #[cfg(target_os = "linux")]
 use crate::firewall::Firewall;
 
-use crate::p2p::{peer::Direction, run_server, FileToSend, PeerEvent, TransferCommand};
-use crate::user_data::UserConfig;
+use crate::p2p::{
+    run_server, FileToSend, PeerEvent, RejectReason, Rejection, TransferCommand, TransferId,
+    TrustedPeers,
+};
+use crate::user_data::{CollisionPolicy, UserConfig};
 use components::{MainLayout, STYLE};
 use dialogs::{AcceptFileDialog, FirewallDialog};
 use events::pool_peers;
@@ -30,6 +36,7 @@ pub fn build_window(
     file_sender: Arc<Mutex<Sender<FileToSend>>>,
     peer_receiver: Arc<Mutex<Receiver<PeerEvent>>>,
     command_sender: Arc<Mutex<Sender<TransferCommand>>>,
+    trusted: TrustedPeers,
     f: fn(&gtk::ApplicationWindow),
 ) -> Result<(), Box<dyn Error>> {
     let title = format!("Dragit {}", env!("CARGO_PKG_VERSION"));
@@ -47,7 +54,11 @@ pub fn build_window(
 
     let alert_notif = AppNotification::new(&overlay, NotificationType::Alert);
     let error_notif = AppNotification::new(&overlay, NotificationType::Error);
-    let progress = ProgressNotification::new(&overlay);
+    let progress = ProgressNotification::new(&overlay, command_sender.clone());
+
+    // Questions about incoming transfers, closed when the sender gives up
+    let accept_dialogs: Rc<RefCell<HashMap<TransferId, AcceptFileDialog>>> =
+        Rc::new(RefCell::new(HashMap::new()));
 
     overlay.add_overlay(&layout.layout);
 
@@ -60,65 +71,155 @@ pub fn build_window(
         file_sender,
         peer_receiver,
         gtk_sender,
+        trusted.clone(),
     );
 
     let window_weak = window.downgrade();
     gtk_receiver.attach(None, move |values| match values {
-        PeerEvent::TransferProgress((v, t, direction)) => {
+        PeerEvent::TransferStarted(id, name, peer, direction) => {
             alert_notif.hide(&overlay);
-            let size = v as f64;
-            let total = t as f64;
-            match direction {
-                Direction::Incoming => progress.show_incoming(&overlay, size, total),
-                Direction::Outgoing => progress.show_outgoing(&overlay, size, total),
-            }
+            progress.show_started(&overlay, id, &name, &peer, &direction);
+            Continue(true)
+        }
+        PeerEvent::TransferProgress((id, current, _)) => {
+            progress.show_progress(&overlay, id, &current);
+            Continue(true)
+        }
+        PeerEvent::TransferPreparing((id, counter, total)) => {
+            progress.show_preparing(&overlay, id, counter, total);
+            Continue(true)
+        }
+        PeerEvent::WaitingForAnswer(id) => {
+            progress.show_waiting(&overlay, id);
+            Continue(true)
+        }
+        PeerEvent::TransferRejected(id, rejection) => {
+            progress.finish(&overlay, id);
+            alert_notif.show_text(&overlay, &rejection.to_string());
+            Continue(true)
+        }
+        PeerEvent::TransferCompleted(id) => {
+            progress.finish(&overlay, id);
+            Continue(true)
+        }
+        PeerEvent::TransferFailed(id, error) => {
+            progress.finish(&overlay, id);
+            error_notif.show_text(&overlay, &error);
+            Continue(true)
+        }
+        PeerEvent::TransferCancelled(id) => {
+            progress.finish(&overlay, id);
+            alert_notif.show_text(&overlay, "Transfer cancelled");
             Continue(true)
         }
-        PeerEvent::WaitingForAnswer => {
-            alert_notif.show_text(&overlay, "Waiting for answer from the other device...");
+        PeerEvent::TransferCancelledByPeer(id) => {
+            progress.finish(&overlay, id);
+            // Binding first, so the map is not borrowed when the dialog answers
+            let dialog = accept_dialogs.borrow_mut().remove(&id);
+            if let Some(dialog) = dialog {
+                dialog.cancel();
+            }
+            alert_notif.show_text(&overlay, "Transfer cancelled by the other device");
             Continue(true)
         }
-        PeerEvent::TransferRejected => {
-            alert_notif.show_text(&overlay, "Payload was rejected");
+        PeerEvent::TransferCorrupted(id) => {
+            progress.finish(&overlay, id);
+            error_notif.show_text(&overlay, "The other device received the payload corrupted");
             Continue(true)
         }
-        PeerEvent::TransferCompleted => {
-            progress.hide(&overlay);
+        PeerEvent::AnswerTimedOut(id) => {
+            let dialog = accept_dialogs.borrow_mut().remove(&id);
+            if let Some(dialog) = dialog {
+                dialog.cancel();
+            }
+            alert_notif.show_text(
+                &overlay,
+                "Incoming transfer denied, it was not answered in time",
+            );
             Continue(true)
         }
         PeerEvent::FileCorrect(file_name, payload) => {
-            progress.progress_bar.set_fraction(0.0);
-            progress.hide(&overlay);
-
             alert_notif.show_payload(&overlay, &file_name, &payload);
             layout.add_recent_file(&file_name, payload);
 
             Continue(true)
         }
-        PeerEvent::FileIncorrect => {
-            progress.progress_bar.set_fraction(0.0);
-            progress.hide(&overlay);
-            error_notif.show_text(&overlay, "File is incorrect");
+        PeerEvent::FileIncorrect(files) => {
+            let text = match files.len() {
+                1 => format!("File is incorrect: {}", files[0]),
+                _ => format!("{} files are incorrect: {}", files.len(), files.join(", ")),
+            };
+            error_notif.show_text(&overlay, &text);
             Continue(true)
         }
-        PeerEvent::FileIncoming(name, hash, size, transfer_type) => {
+        PeerEvent::FileIncoming(id, name, _, size, transfer_type, count, mut sender, available) => {
             if let Some(win) = window_weak.upgrade() {
-                let accept_dialog = AcceptFileDialog::new(&win, name, size, transfer_type);
-                let response = accept_dialog.run();
+                sender.paired = trusted.contains(&sender.peer_id);
+                let accept_dialog = AcceptFileDialog::new(
+                    &win,
+                    name,
+                    size,
+                    transfer_type,
+                    count,
+                    &sender,
+                    available,
+                );
+
+                let dialogs = accept_dialogs.clone();
+                let command_sender = command_sender.clone();
+                accept_dialog.show(move |response, note| {
+                    // Transfer cancelled by the sender doesn't need the answer anymore
+                    if dialogs.borrow_mut().remove(&id).is_none() {
+                        return;
+                    }
+                    let command = match response {
+                        gtk::ResponseType::Yes => TransferCommand::Accept(id),
+                        _ => {
+                            let rejection = Rejection::with_note(RejectReason::Declined, note);
+                            TransferCommand::Deny(id, rejection)
+                        }
+                    };
 
-                let command = match response {
-                    gtk::ResponseType::Yes => TransferCommand::Accept(hash),
-                    gtk::ResponseType::No => TransferCommand::Deny(hash),
-                    _ => TransferCommand::Deny(hash),
-                };
+                    let _ = command_sender.lock().unwrap().try_send(command);
+                });
+                accept_dialogs.borrow_mut().insert(id, accept_dialog);
+            }
+            Continue(true)
+        }
+        PeerEvent::FileExists(id, name) => {
+            if let Some(win) = window_weak.upgrade() {
+                let collision_dialog = AcceptFileDialog::new_for_collision(&win, &name);
+
+                let dialogs = accept_dialogs.clone();
+                let command_sender = command_sender.clone();
+                collision_dialog.show(move |response, _| {
+                    if dialogs.borrow_mut().remove(&id).is_none() {
+                        return;
+                    }
+                    let policy = match response {
+                        gtk::ResponseType::Yes => CollisionPolicy::Rename,
+                        gtk::ResponseType::Accept => CollisionPolicy::Overwrite,
+                        _ => CollisionPolicy::Skip,
+                    };
+                    let command = TransferCommand::Resolve(id, policy);
 
-                let _ = command_sender.lock().unwrap().try_send(command);
+                    let _ = command_sender.lock().unwrap().try_send(command);
+                });
+                accept_dialogs.borrow_mut().insert(id, collision_dialog);
             }
             Continue(true)
         }
+        PeerEvent::FileSkipped(name) => {
+            alert_notif.show_text(&overlay, &format!("Skipped {}, it already exists", name));
+            Continue(true)
+        }
+        PeerEvent::IncompatibleVersion(peer) => {
+            error_notif.show_text(&overlay, &peer.incompatible_hint());
+            Continue(true)
+        }
         PeerEvent::Error(error) => {
             error!("Got error: {}", error);
-            progress.hide(&overlay);
+            // Errors of the transfers come with their ids, so the progress of the others stays
 
             let error = format!("Encountered an error: {:?}", error);
             error_notif.show_text(&overlay, &error);
@@ -187,10 +288,14 @@ pub fn start_window(name: String) {
     let (peer_sender, peer_receiver) = bounded::<PeerEvent>(1024 * 24);
     let (command_sender, command_receiver) = bounded::<TransferCommand>(1024 * 24);
 
+    // Paired devices are read once, the window keeps the list up to date
+    let trusted = TrustedPeers::from_config();
+
     // Start the p2p server in separate thread
     let sender_clone = peer_sender.clone();
-    thread::spawn(
-        move || match run_server(peer_sender, file_receiver, command_receiver) {
+    let server_trusted = trusted.clone();
+    thread::spawn(move || {
+        match run_server(peer_sender, file_receiver, command_receiver, server_trusted) {
             Ok(_) => {}
             Err(e) => {
                 error!("Server error: {:?}", e);
@@ -198,8 +303,8 @@ pub fn start_window(name: String) {
                     .try_send(PeerEvent::Error(e.to_string()))
                     .unwrap();
             }
-        },
-    );
+        }
+    });
 
     let peer_receiver_arc = Arc::new(Mutex::new(peer_receiver));
     let application = gtk::Application::new(Some(&name), gio::ApplicationFlags::empty());
@@ -224,6 +329,7 @@ pub fn start_window(name: String) {
             file_sender_c,
             peer_receiver_c,
             command_sender_c,
+            trusted.clone(),
             |window| match handle_firewall(window) {
                 Ok(_) => {}
                 Err(e) => error!("Firewall handling error: {}", e),
@@ -1,39 +1,109 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_std::channel::Sender;
 use gtk::prelude::*;
 use gtk::Label;
 
-use crate::dnd::components::get_link;
-use crate::p2p::Payload;
+use crate::dnd::components::{get_batch_link, get_link};
+use crate::p2p::peer::{Direction, Progress};
+use crate::p2p::{Payload, Peer, TransferCommand, TransferId};
+
+/// Single transfer shown in the progress notification, with its own cancel button
+struct ProgressRow {
+    layout: gtk::Box,
+    progress_bar: gtk::ProgressBar,
+    title: String,
+}
+
+impl ProgressRow {
+    fn new(
+        id: TransferId,
+        title: String,
+        command_sender: Arc<Mutex<Sender<TransferCommand>>>,
+    ) -> Self {
+        let layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+        let progress_bar = gtk::ProgressBar::new();
+
+        progress_bar.set_text(Some(&title));
+        progress_bar.set_show_text(true);
+        progress_bar.set_halign(gtk::Align::Center);
+        progress_bar.set_valign(gtk::Align::Start);
+        progress_bar.set_hexpand(true);
+        progress_bar.set_size_request(500, 50);
+
+        let button_cancel =
+            gtk::Button::from_icon_name(Some("process-stop-symbolic"), gtk::IconSize::SmallToolbar);
+        button_cancel.set_widget_name("button-close");
+        button_cancel.set_relief(gtk::ReliefStyle::None);
+        button_cancel.set_tooltip_text(Some("Cancel transfer"));
+        button_cancel.set_valign(gtk::Align::Center);
+
+        button_cancel.connect_clicked(move |_| {
+            let _ = command_sender
+                .lock()
+                .unwrap()
+                .try_send(TransferCommand::Cancel(id));
+        });
+
+        layout.pack_start(&progress_bar, true, false, 0);
+        layout.pack_start(&button_cancel, false, false, 0);
+        layout.show_all();
 
+        ProgressRow {
+            layout,
+            progress_bar,
+            title,
+        }
+    }
+
+    fn set_text(&self, fraction: f64, text: &str) {
+        self.progress_bar.set_fraction(fraction);
+        self.progress_bar
+            .set_text(Some(&format!("{}: {}", self.title, text)));
+    }
+
+    fn set_progress(&self, progress: &Progress) {
+        self.set_text(progress.fraction(), &progress.to_string());
+    }
+}
+
+fn fraction(counter: usize, total: usize) -> f64 {
+    if total > 0 {
+        (counter as f64 / total as f64).min(1.0)
+    } else {
+        1.0
+    }
+}
+
+/// Progress of all running transfers, one row each. The notification is hidden
+/// once the last of them finishes.
 pub struct ProgressNotification {
     revealer: gtk::Revealer,
     overlay: gtk::Overlay,
-    pub progress_bar: gtk::ProgressBar,
+    rows_layout: gtk::Box,
+    rows: RefCell<HashMap<TransferId, ProgressRow>>,
+    command_sender: Arc<Mutex<Sender<TransferCommand>>>,
 }
 
 impl ProgressNotification {
-    pub fn new(main_overlay: &gtk::Overlay) -> Self {
-        let layout = gtk::Box::new(gtk::Orientation::Horizontal, 5);
-        layout.set_widget_name("notification");
+    pub fn new(
+        main_overlay: &gtk::Overlay,
+        command_sender: Arc<Mutex<Sender<TransferCommand>>>,
+    ) -> Self {
+        let rows_layout = gtk::Box::new(gtk::Orientation::Vertical, 5);
+        rows_layout.set_widget_name("notification");
 
         let overlay = gtk::Overlay::new();
         let revealer = gtk::Revealer::new();
-        let progress_bar = gtk::ProgressBar::new();
 
         revealer.set_halign(gtk::Align::Center);
         revealer.set_valign(gtk::Align::Start);
         revealer.set_transition_type(gtk::RevealerTransitionType::SlideDown);
-
-        progress_bar.set_text(Some("Receiving file"));
-        progress_bar.set_show_text(true);
-
-        progress_bar.set_halign(gtk::Align::Center);
-        progress_bar.set_valign(gtk::Align::Start);
-        progress_bar.set_hexpand(true);
-        progress_bar.set_size_request(500, 50);
         revealer.set_margin_bottom(30);
 
-        layout.pack_start(&progress_bar, true, false, 0);
-        revealer.add(&layout);
+        revealer.add(&rows_layout);
 
         overlay.add_overlay(&revealer);
 
@@ -43,7 +113,9 @@ impl ProgressNotification {
         ProgressNotification {
             revealer,
             overlay,
-            progress_bar,
+            rows_layout,
+            rows: RefCell::new(HashMap::new()),
+            command_sender,
         }
     }
 
@@ -52,24 +124,77 @@ impl ProgressNotification {
         self.revealer.set_reveal_child(true)
     }
 
-    fn show_progress(&self, main_overlay: &gtk::Overlay, size: f64, total: f64, text: &str) {
+    fn add_row(&self, id: TransferId, title: String) {
+        let row = ProgressRow::new(id, title, self.command_sender.clone());
+        self.rows_layout.pack_start(&row.layout, false, false, 0);
+        if let Some(old) = self.rows.borrow_mut().insert(id, row) {
+            self.rows_layout.remove(&old.layout);
+        }
+    }
+
+    /// Runs `update` on the row of the transfer, the row is added if it's not there yet
+    fn update_row<F: Fn(&ProgressRow)>(
+        &self,
+        main_overlay: &gtk::Overlay,
+        id: TransferId,
+        update: F,
+    ) {
+        if !self.rows.borrow().contains_key(&id) {
+            self.add_row(id, format!("Transfer {}", id));
+        }
+        if let Some(row) = self.rows.borrow().get(&id) {
+            update(row);
+        }
         self.show(main_overlay);
-        self.progress_bar.set_fraction(size / total);
-        self.progress_bar.set_text(Some(text));
     }
 
-    pub fn show_incoming(&self, main_overlay: &gtk::Overlay, size: f64, total: f64) {
-        self.show_progress(main_overlay, size, total, "Receiving file");
+    pub fn show_started(
+        &self,
+        main_overlay: &gtk::Overlay,
+        id: TransferId,
+        name: &str,
+        peer: &Peer,
+        direction: &Direction,
+    ) {
+        let title = match direction {
+            Direction::Incoming => format!("Receiving {} from {}", name, peer.display_name()),
+            Direction::Outgoing => format!("Sending {} to {}", name, peer.display_name()),
+        };
+        self.add_row(id, title);
+        self.update_row(main_overlay, id, |row| row.set_text(0.0, "starting"));
     }
 
-    pub fn show_outgoing(&self, main_overlay: &gtk::Overlay, size: f64, total: f64) {
-        self.show_progress(main_overlay, size, total, "Sending file");
+    pub fn show_progress(&self, main_overlay: &gtk::Overlay, id: TransferId, progress: &Progress) {
+        self.update_row(main_overlay, id, |row| row.set_progress(progress));
     }
 
-    pub fn hide(&self, main_overlay: &gtk::Overlay) {
-        main_overlay.reorder_overlay(&self.overlay, 0);
+    pub fn show_preparing(
+        &self,
+        main_overlay: &gtk::Overlay,
+        id: TransferId,
+        counter: usize,
+        total: usize,
+    ) {
+        self.update_row(main_overlay, id, |row| {
+            row.set_text(fraction(counter, total), "preparing")
+        });
+    }
 
-        self.revealer.set_reveal_child(false)
+    pub fn show_waiting(&self, main_overlay: &gtk::Overlay, id: TransferId) {
+        self.update_row(main_overlay, id, |row| {
+            row.set_text(0.0, "waiting for answer from the other device")
+        });
+    }
+
+    /// Removes the row of the finished transfer, no matter how it ended
+    pub fn finish(&self, main_overlay: &gtk::Overlay, id: TransferId) {
+        if let Some(row) = self.rows.borrow_mut().remove(&id) {
+            self.rows_layout.remove(&row.layout);
+        }
+        if self.rows.borrow().is_empty() {
+            main_overlay.reorder_overlay(&self.overlay, 0);
+            self.revealer.set_reveal_child(false)
+        }
     }
 }
 
@@ -194,6 +319,12 @@ impl AppNotification {
                 self.remove_link();
                 self.label.set_text("Received text");
             }
+            Payload::Batch(paths) => {
+                self.label.set_text("Received");
+                self.remove_link();
+                let link = get_batch_link(file_name, &paths);
+                self.layout.attach(&link, self.link_pos, 0, 1, 1);
+            }
         };
 
         self.reveal(overlay);
@@ -1,6 +1,7 @@
 #[macro_use]
 extern crate log;
 
+pub mod cli;
 pub mod dnd;
 
 #[cfg(target_os = "linux")]
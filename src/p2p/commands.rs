@@ -1,5 +1,240 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::channel::oneshot;
+
+use crate::p2p::transfer::metadata::Rejection;
+use crate::user_data::CollisionPolicy;
+
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies a single transfer, so the answers and progress can be matched
+/// with it even when several transfers are running at the same time.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TransferId(u64);
+
+impl TransferId {
+    pub fn generate() -> TransferId {
+        TransferId(NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for TransferId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
 #[derive(Debug)]
 pub enum TransferCommand {
-    Accept(String),
-    Deny(String),
+    Accept(TransferId),
+    /// Rejection is passed to the sender, so it can tell the user why
+    Deny(TransferId, Rejection),
+    Cancel(TransferId),
+    /// Answer for the incoming payload, which name is already taken
+    Resolve(TransferId, CollisionPolicy),
+}
+
+impl TransferCommand {
+    pub fn transfer_id(&self) -> TransferId {
+        match self {
+            TransferCommand::Accept(id) => *id,
+            TransferCommand::Deny(id, _) => *id,
+            TransferCommand::Cancel(id) => *id,
+            TransferCommand::Resolve(id, _) => *id,
+        }
+    }
+}
+
+/// Incoming transfers waiting for the user's decision. Each handler registers
+/// its transfer and gets only the command addressed to it.
+#[derive(Debug, Clone, Default)]
+pub struct PendingAnswers(Arc<Mutex<HashMap<TransferId, oneshot::Sender<TransferCommand>>>>);
+
+impl PendingAnswers {
+    fn lock(&self) -> MutexGuard<'_, HashMap<TransferId, oneshot::Sender<TransferCommand>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn register(&self, id: TransferId) -> oneshot::Receiver<TransferCommand> {
+        let (sender, receiver) = oneshot::channel();
+        self.lock().insert(id, sender);
+        receiver
+    }
+
+    pub fn remove(&self, id: &TransferId) {
+        self.lock().remove(id);
+    }
+
+    /// Number of transfers waiting for the decision
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Passes the command to the transfer it belongs to. Answers for transfers
+    /// which are already gone are dropped.
+    pub fn route(&self, command: TransferCommand) {
+        let id = command.transfer_id();
+        match self.lock().remove(&id) {
+            Some(sender) => {
+                if let Err(command) = sender.send(command) {
+                    warn!("Transfer {} is not waiting anymore: {:?}", id, command);
+                }
+            }
+            None => warn!("No transfer waiting for {:?}", command),
+        }
+    }
+}
+
+/// Transfers which are running at the moment, both incoming and outgoing.
+/// Each of them can be cancelled until it's finished.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveTransfers(Arc<Mutex<HashMap<TransferId, oneshot::Sender<()>>>>);
+
+impl ActiveTransfers {
+    fn lock(&self) -> MutexGuard<'_, HashMap<TransferId, oneshot::Sender<()>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the receiver, which resolves once the transfer is cancelled
+    pub fn register(&self, id: TransferId) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.lock().insert(id, sender);
+        receiver
+    }
+
+    pub fn remove(&self, id: &TransferId) {
+        self.lock().remove(id);
+    }
+
+    /// Returns false if there is no such transfer running
+    pub fn cancel(&self, id: &TransferId) -> bool {
+        match self.lock().remove(id) {
+            Some(sender) => {
+                info!("Cancelling transfer {}", id);
+                let _ = sender.send(());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Digests of the files being received at the moment. Partial data is named after
+/// the digest, so two transfers of the same file would write into the same place.
+#[derive(Debug, Clone, Default)]
+pub struct ReceivingFiles(Arc<Mutex<HashSet<String>>>);
+
+impl ReceivingFiles {
+    fn lock(&self) -> MutexGuard<'_, HashSet<String>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns None if the file is already being received.
+    /// The file is released once the claim is dropped.
+    pub fn claim(&self, hash: &str) -> Option<ReceivingClaim> {
+        if !self.lock().insert(hash.to_string()) {
+            return None;
+        }
+        Some(ReceivingClaim {
+            files: self.clone(),
+            hash: hash.to_string(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ReceivingClaim {
+    files: ReceivingFiles,
+    hash: String,
+}
+
+impl Drop for ReceivingClaim {
+    fn drop(&mut self) {
+        self.files.lock().remove(&self.hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::commands::{
+        ActiveTransfers, PendingAnswers, ReceivingFiles, TransferCommand, TransferId,
+    };
+    use crate::p2p::transfer::metadata::{RejectReason, Rejection};
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_transfer_ids_are_unique() {
+        let first = TransferId::generate();
+        let second = TransferId::generate();
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_receiving_claim() {
+        let files = ReceivingFiles::default();
+
+        let claim = files.claim("a909b834a8f95194ee2ce975e38cec31");
+        assert!(claim.is_some());
+        assert!(files.claim("a909b834a8f95194ee2ce975e38cec31").is_none());
+        assert!(files.claim("e80b5017098950fc58aad83c8c14978e").is_some());
+
+        drop(claim);
+        assert!(files.claim("a909b834a8f95194ee2ce975e38cec31").is_some());
+    }
+
+    #[test]
+    fn test_route_to_matching_transfer() {
+        let answers = PendingAnswers::default();
+        let first = TransferId::generate();
+        let second = TransferId::generate();
+        let first_answer = answers.register(first);
+        let second_answer = answers.register(second);
+
+        let rejection = Rejection::new(RejectReason::Declined);
+        answers.route(TransferCommand::Deny(second, rejection));
+        answers.route(TransferCommand::Accept(first));
+
+        assert!(matches!(
+            block_on(first_answer),
+            Ok(TransferCommand::Accept(id)) if id == first
+        ));
+        assert!(matches!(
+            block_on(second_answer),
+            Ok(TransferCommand::Deny(id, rejection))
+                if id == second && rejection.reason == RejectReason::Declined
+        ));
+    }
+
+    #[test]
+    fn test_route_unknown_transfer() {
+        let answers = PendingAnswers::default();
+        let id = TransferId::generate();
+        let answer = answers.register(id);
+
+        answers.route(TransferCommand::Accept(TransferId::generate()));
+        assert_eq!(answers.len(), 1);
+        answers.remove(&id);
+        assert!(answers.is_empty());
+
+        assert!(block_on(answer).is_err());
+    }
+
+    #[test]
+    fn test_cancel_active_transfer() {
+        let transfers = ActiveTransfers::default();
+        let id = TransferId::generate();
+        let cancelled = transfers.register(id);
+
+        assert!(transfers.cancel(&id));
+        assert!(block_on(cancelled).is_ok());
+        assert!(!transfers.cancel(&id));
+    }
 }
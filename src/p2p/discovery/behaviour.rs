@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashSet, VecDeque},
     error::Error,
     task::{Context, Poll},
     time::Duration,
@@ -14,24 +14,30 @@ use libp2p::swarm::{
 };
 
 use crate::p2p::discovery::handler::KeepAliveHandler;
-use crate::p2p::discovery::protocol::{Discovery, DiscoveryEvent};
-use crate::p2p::peer::{CurrentPeers, OperatingSystem, Peer, PeerEvent};
+use crate::p2p::discovery::protocol::{Discovery, DiscoveryEvent, HostEvent};
+use crate::p2p::pairing::TrustedPeers;
+use crate::p2p::peer::{CurrentPeers, OperatingSystem, Peer, PeerEvent, PeerTable};
 
-type Handler = KeepAliveHandler<Discovery, Discovery, Discovery>;
+type Handler = KeepAliveHandler<Discovery, Discovery, HostEvent>;
 
 pub struct DiscoveryBehaviour {
     events: VecDeque<NetworkBehaviourAction<DiscoveryEvent, Handler>>,
-    peers: HashMap<PeerId, Peer>,
+    peers: PeerTable,
+    trusted: TrustedPeers,
+    /// Peers without a common protocol version, they are not dialed again
+    incompatible: HashSet<PeerId>,
     hostname: String,
     os: OperatingSystem,
     sender: Sender<PeerEvent>,
 }
 
 impl DiscoveryBehaviour {
-    pub fn new(sender: Sender<PeerEvent>) -> Self {
+    pub fn new(sender: Sender<PeerEvent>, peers: PeerTable, trusted: TrustedPeers) -> Self {
         DiscoveryBehaviour {
             events: VecDeque::new(),
-            peers: HashMap::new(),
+            peers,
+            trusted,
+            incompatible: HashSet::new(),
             hostname: Self::get_hostname(),
             os: Self::get_os(),
             sender,
@@ -59,10 +65,15 @@ impl DiscoveryBehaviour {
     }
 
     fn peers_event(&mut self) -> CurrentPeers {
+        // Devices can be paired in the meantime, so the flag is set on every update
         self.peers
+            .lock()
             .clone()
             .into_iter()
-            .map(|(_, peer)| peer.to_owned())
+            .map(|(_, mut peer)| {
+                peer.paired = self.trusted.contains(&peer.peer_id);
+                peer
+            })
             .collect::<CurrentPeers>()
     }
 
@@ -84,21 +95,19 @@ impl DiscoveryBehaviour {
         });
 
         if insert_peer {
-            let peer = Peer {
-                name: peer_id.to_base58(),
-                peer_id: peer_id.clone(),
-                address: addr,
-                hostname: "Not known yet".to_string(),
-                os: OperatingSystem::Unknown,
-            };
-            self.peers.insert(peer_id, peer);
+            let peer = Peer::new(peer_id.clone(), addr);
+            self.peers.lock().insert(peer_id, peer);
         }
     }
 
     pub fn add_peer(&mut self, peer_id: PeerId, addr: Multiaddr) {
-        match self.peers.get(&peer_id) {
+        if self.incompatible.contains(&peer_id) {
+            return;
+        }
+        let known_os = self.peers.get(&peer_id).map(|peer| peer.os);
+        match known_os {
             // Keep dialing if server didn't get host details yet
-            Some(peer) if peer.os == OperatingSystem::Unknown => {
+            Some(OperatingSystem::Unknown) => {
                 info!("OS unknown, dialing... {:?}", peer_id);
                 self.dial_peer(peer_id, addr, false);
             }
@@ -111,7 +120,8 @@ impl DiscoveryBehaviour {
     }
 
     pub fn remove_peer(&mut self, peer_id: &PeerId) -> Result<(), Box<dyn Error>> {
-        self.peers.remove(peer_id);
+        self.peers.lock().remove(peer_id);
+        self.incompatible.remove(peer_id);
 
         if let Err(e) = self.notify_frontend() {
             error!("Failed to notify the frontend: {:?}", e);
@@ -119,8 +129,21 @@ impl DiscoveryBehaviour {
         Ok(())
     }
 
+    /// Shows the hint only once, the peer may be discovered many times
+    pub fn mark_incompatible(&mut self, peer_id: PeerId) {
+        if !self.incompatible.insert(peer_id) {
+            return;
+        }
+        warn!("Peer {} uses an incompatible protocol version", peer_id);
+
+        let peer = self.peers.resolve(&peer_id);
+        if let Err(e) = self.sender.try_send(PeerEvent::IncompatibleVersion(peer)) {
+            error!("Failed to notify the frontend: {:?}", e);
+        }
+    }
+
     pub fn update_peer(&mut self, peer_id: PeerId, hostname: String, os: OperatingSystem) {
-        match self.peers.get_mut(&peer_id) {
+        match self.peers.lock().get_mut(&peer_id) {
             Some(peer) => {
                 info!("Updating peer. {:?}", peer_id);
                 peer.hostname = hostname;
@@ -171,7 +194,7 @@ impl NetworkBehaviour for DiscoveryBehaviour {
                 address,
                 role_override: _,
             } => {
-                if let Some(peer) = self.peers.get_mut(peer_id) {
+                if let Some(peer) = self.peers.lock().get_mut(peer_id) {
                     info!("Dialer, updating the address");
                     peer.address = address.clone();
                 };
@@ -197,7 +220,8 @@ impl NetworkBehaviour for DiscoveryBehaviour {
                 };
                 self.events.push_back(event);
 
-                match self.peers.get_mut(peer_id) {
+                let mut peers = self.peers.lock();
+                match peers.get_mut(peer_id) {
                     Some(peer) => {
                         info!("Listener: peer exists, updating address.");
                         peer.address = send_back_addr.to_owned();
@@ -207,14 +231,8 @@ impl NetworkBehaviour for DiscoveryBehaviour {
                     // make sure to add new peer.
                     None => {
                         info!("Listener: peer not found, adding new one.");
-                        let peer = Peer {
-                            name: peer_id.to_base58(),
-                            peer_id: peer_id.clone(),
-                            address: send_back_addr.to_owned(),
-                            hostname: "Not known yet".to_string(),
-                            os: OperatingSystem::Unknown,
-                        };
-                        self.peers.insert(peer_id.to_owned(), peer);
+                        let peer = Peer::new(peer_id.clone(), send_back_addr.to_owned());
+                        peers.insert(peer_id.to_owned(), peer);
                     }
                 }
             }
@@ -230,14 +248,19 @@ impl NetworkBehaviour for DiscoveryBehaviour {
         _remaining_established: usize,
     ) {
         info!("Peer disconnected: {:?}", peer);
-        self.peers.remove(peer);
+        self.peers.lock().remove(peer);
+        self.incompatible.remove(peer);
 
         if let Err(e) = self.notify_frontend() {
             error!("Failed to notify the frontend: {:?}", e);
         }
     }
 
-    fn inject_event(&mut self, peer: PeerId, _connection: ConnectionId, event: Discovery) {
+    fn inject_event(&mut self, peer: PeerId, _connection: ConnectionId, event: HostEvent) {
+        let event = match event {
+            HostEvent::Host(event) => event,
+            HostEvent::Incompatible => return self.mark_incompatible(peer),
+        };
         let message = DiscoveryEvent {
             peer,
             hostname: event.hostname,
@@ -7,6 +7,11 @@ use std::fmt::Debug;
 use smallvec::SmallVec;
 use std::{error, task::Context, task::Poll, time::Duration};
 
+use crate::p2p::util;
+
+/// Outbound negotiation failed, because the remote doesn't know any of our protocol versions
+pub struct NoCommonProtocol;
+
 /// Shamelessly copied OneShotHandler that keeps the connections open
 pub struct KeepAliveHandler<TInbound, TOutbound, TEvent>
 where
@@ -95,7 +100,7 @@ where
     TOutbound::Output: Into<TEvent>,
     TOutbound::Error: error::Error + Send + 'static,
     SubstreamProtocol<TInbound, ()>: Clone,
-    TEvent: Debug + Send + 'static,
+    TEvent: From<NoCommonProtocol> + Debug + Send + 'static,
 {
     type InEvent = TOutbound;
     type OutEvent = TEvent;
@@ -135,6 +140,13 @@ where
         _info: Self::OutboundOpenInfo,
         error: ConnectionHandlerUpgrErr<<Self::OutboundProtocol as OutboundUpgradeSend>::Error>,
     ) {
+        self.dial_negotiated -= 1;
+
+        // Peer running other version is still reported, so the user knows why it's not usable
+        if util::is_incompatible(&error) {
+            self.events_out.push(NoCommonProtocol.into());
+            return;
+        }
         if self.pending_error.is_none() {
             self.pending_error = Some(error);
         }
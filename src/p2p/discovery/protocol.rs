@@ -1,14 +1,18 @@
-use std::{fmt, io, iter, pin::Pin};
+use std::{fmt, io, pin::Pin};
 
 use futures::prelude::*;
 use libp2p::core::{upgrade, InboundUpgrade, OutboundUpgrade, PeerId, UpgradeInfo};
 use prost::Message;
 
+use super::handler::NoCommonProtocol;
 use super::proto::Host;
 
 use crate::p2p::peer::OperatingSystem;
 use crate::p2p::util::TSocketAlias;
 
+/// Supported versions, newest first, so the highest common one gets negotiated
+pub const PROTOCOL_VERSIONS: [&str; 1] = ["/discovery/1.0"];
+
 #[derive(Debug)]
 pub struct DiscoveryEvent {
     pub peer: PeerId,
@@ -26,6 +30,26 @@ impl fmt::Display for DiscoveryEvent {
     }
 }
 
+/// Output of the discovery handler
+#[derive(Debug)]
+pub enum HostEvent {
+    Host(Discovery),
+    /// Remote peer doesn't support any of our discovery protocol versions
+    Incompatible,
+}
+
+impl From<Discovery> for HostEvent {
+    fn from(discovery: Discovery) -> Self {
+        HostEvent::Host(discovery)
+    }
+}
+
+impl From<NoCommonProtocol> for HostEvent {
+    fn from(_: NoCommonProtocol) -> Self {
+        HostEvent::Incompatible
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Discovery {
     pub hostname: String,
@@ -43,10 +67,10 @@ impl Default for Discovery {
 
 impl UpgradeInfo for Discovery {
     type Info = &'static str;
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = Vec<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once("/discovery/1.0")
+        PROTOCOL_VERSIONS.to_vec()
     }
 }
 
@@ -1,13 +1,11 @@
-use std::{error::Error, sync::Arc, thread::sleep, time::Duration};
+use std::{error::Error, thread::sleep, time::Duration};
 
 use async_std::channel::{Receiver, Sender};
-use async_std::sync::Mutex;
 
 use futures::{executor, select, stream::StreamExt, FutureExt};
 use libp2p::{
     core::transport::Transport,
     core::upgrade,
-    identity,
     mdns::{Mdns, MdnsConfig, MdnsEvent},
     mplex, noise,
     swarm::NetworkBehaviourEventProcess,
@@ -17,15 +15,18 @@ use libp2p::{
 
 pub mod commands;
 pub mod discovery;
+pub mod pairing;
 pub mod peer;
 pub mod transfer;
 pub mod util;
 
-use crate::user_data::UserConfig;
-pub use commands::TransferCommand;
+use crate::p2p::transfer::file::HashCache;
+use crate::user_data::{self, UserConfig};
+pub use commands::{TransferCommand, TransferId};
 pub use discovery::{DiscoveryBehaviour, DiscoveryEvent};
-pub use peer::{CurrentPeers, OperatingSystem, Peer, PeerEvent, TransferType};
-pub use transfer::metadata::hash_contents;
+pub use pairing::TrustedPeers;
+pub use peer::{CurrentPeers, OperatingSystem, Peer, PeerEvent, PeerTable, TransferType};
+pub use transfer::metadata::{hash_contents, RejectReason, Rejection};
 pub use transfer::{FileToSend, Payload, TransferBehaviour, TransferOut, TransferPayload};
 
 #[derive(NetworkBehaviour)]
@@ -82,9 +83,12 @@ impl NetworkBehaviourEventProcess<TransferPayload> for MyBehaviour {
                     error!("{:?}", e);
                 }
             }
-            Err(e) => {
-                warn!("File not correct: {:?}", e);
-                if let Err(e) = event.sender_queue.try_send(PeerEvent::FileIncorrect) {
+            Err(corrupted) => {
+                warn!("Files not correct: {:?}", corrupted);
+                if let Err(e) = event
+                    .sender_queue
+                    .try_send(PeerEvent::FileIncorrect(corrupted))
+                {
                     error!("{:?}", e);
                 }
                 if let Err(e) = event.cleanup() {
@@ -105,17 +109,27 @@ async fn execute_swarm(
     sender: Sender<PeerEvent>,
     receiver: Receiver<FileToSend>,
     command_receiver: Receiver<TransferCommand>,
+    trusted: TrustedPeers,
 ) -> Result<(), Box<dyn Error>> {
-    let local_keys = identity::Keypair::generate_ed25519();
+    let config = UserConfig::new()?;
+
+    // Transfers interrupted by the previous shutdown leave their partial data behind
+    if let Err(e) = user_data::remove_stale_partials(&config.get_downloads_dir()) {
+        warn!("Could not remove stale partial data: {:?}", e);
+    }
+
+    // Keypair is persistent, so the device keeps the same PeerId across restarts
+    let local_keys = config.get_keypair()?;
     let local_peer_id = PeerId::from(local_keys.public());
     info!("I am Peer: {:?}", local_peer_id);
 
-    let command_rec = Arc::new(Mutex::new(command_receiver));
-    let command_receiver_c = Arc::clone(&command_rec);
-
     let mut swarm = {
-        let transfer_behaviour = TransferBehaviour::new(sender.clone(), command_receiver_c, None);
-        let discovery = DiscoveryBehaviour::new(sender);
+        // Discovery fills the table, transfers look up the sending peers in it
+        let peers = PeerTable::default();
+        let mut transfer_behaviour =
+            TransferBehaviour::new(sender.clone(), command_receiver, None, peers.clone());
+        transfer_behaviour.hashes = HashCache::load(config.get_hash_cache_path());
+        let discovery = DiscoveryBehaviour::new(sender, peers, trusted);
         let mdns = Mdns::new(MdnsConfig::default()).await?;
         let behaviour = MyBehaviour {
             mdns,
@@ -144,7 +158,6 @@ async fn execute_swarm(
         Swarm::new(transport, behaviour, local_peer_id)
     };
 
-    let config = UserConfig::new()?;
     let port = config.get_port();
 
     let address = format!("/ip4/0.0.0.0/tcp/{}", port);
@@ -172,6 +185,7 @@ pub fn run_server(
     sender: Sender<PeerEvent>,
     file_receiver: Receiver<FileToSend>,
     command_receiver: Receiver<TransferCommand>,
+    trusted: TrustedPeers,
 ) -> Result<(), Box<dyn Error>> {
     loop {
         match util::check_network_interfaces() {
@@ -184,7 +198,7 @@ pub fn run_server(
         };
     }
 
-    let future = execute_swarm(sender, file_receiver, command_receiver);
+    let future = execute_swarm(sender, file_receiver, command_receiver, trusted);
     executor::block_on(future)?;
     Ok(())
 }
@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+use crate::user_data::UserConfig;
+
+const PAIRING_CONTEXT: &[u8] = b"dragit-pairing-v1";
+
+/// Devices paired with this one. The list is read from the config once, and then
+/// shared by the window, which adds new pairings, and the discovery, which marks the peers.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedPeers(Arc<Mutex<HashSet<PeerId>>>);
+
+impl TrustedPeers {
+    pub fn new(peers: &[String]) -> TrustedPeers {
+        let peers = peers
+            .iter()
+            .filter_map(|peer| match peer.parse::<PeerId>() {
+                Ok(peer_id) => Some(peer_id),
+                Err(e) => {
+                    warn!("Invalid trusted peer {}: {:?}", peer, e);
+                    None
+                }
+            })
+            .collect();
+        TrustedPeers(Arc::new(Mutex::new(peers)))
+    }
+
+    /// Nobody is trusted if the config can't be read
+    pub fn from_config() -> TrustedPeers {
+        match UserConfig::new() {
+            Ok(config) => Self::new(&config.get_trusted_peers()),
+            Err(e) => {
+                error!("Failed to read trusted peers: {:?}", e);
+                TrustedPeers::default()
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashSet<PeerId>> {
+        // The set holds plain data, so it's fine to use it even after a panic elsewhere
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.lock().contains(peer_id)
+    }
+
+    /// Only updates the shared list, the config is saved by the caller
+    pub fn insert(&self, peer_id: PeerId) {
+        self.lock().insert(peer_id);
+    }
+}
+
+/// Short authentication string shown on both devices during pairing.
+///
+/// PeerIds of ed25519 keys embed the public key itself, so the code is derived
+/// from both public keys. Keys are sorted first, which makes the code the same
+/// no matter which device calculates it.
+pub fn verification_code(local: &PeerId, remote: &PeerId) -> String {
+    let (local, remote) = (local.to_bytes(), remote.to_bytes());
+    let (first, second) = if local <= remote {
+        (local, remote)
+    } else {
+        (remote, local)
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(PAIRING_CONTEXT);
+    hasher.update(&first);
+    hasher.update(&second);
+    let digest = hasher.finalize();
+
+    let number = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+    format!("{:03} {:03}", number / 1000, number % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::pairing::{verification_code, TrustedPeers};
+    use libp2p::PeerId;
+
+    #[test]
+    fn test_verification_code_is_symmetric() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+
+        assert_eq!(verification_code(&a, &b), verification_code(&b, &a));
+    }
+
+    #[test]
+    fn test_verification_code_format() {
+        let code = verification_code(&PeerId::random(), &PeerId::random());
+
+        assert_eq!(code.len(), 7);
+        assert_eq!(&code[3..4], " ");
+        assert!(code.replace(" ", "").chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_trusted_peers() {
+        let (paired, other) = (PeerId::random(), PeerId::random());
+        let trusted = TrustedPeers::new(&[paired.to_base58(), "not a peer id".to_string()]);
+        let shared = trusted.clone();
+
+        assert!(trusted.contains(&paired));
+        assert!(!trusted.contains(&other));
+
+        shared.insert(other);
+        assert!(trusted.contains(&other));
+    }
+
+    #[test]
+    fn test_verification_code_differs_between_devices() {
+        let a = PeerId::random();
+        let codes: Vec<String> = (0..10)
+            .map(|_| verification_code(&a, &PeerId::random()))
+            .collect();
+
+        assert!(codes.iter().any(|code| *code != codes[0]));
+    }
+}
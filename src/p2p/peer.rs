@@ -1,9 +1,14 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
+use bytesize::ByteSize;
 use libp2p::{Multiaddr, PeerId};
 use prost::Enumeration;
 
-use crate::p2p::Payload;
+use crate::p2p::commands::TransferId;
+use crate::p2p::{Payload, Rejection};
 
 #[derive(Debug, Clone)]
 pub enum Direction {
@@ -11,26 +16,159 @@ pub enum Direction {
     Outgoing,
 }
 
+/// Snapshot of the running transfer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Bytes done so far, including the ones from the previous attempt
+    pub counter: usize,
+    pub total: usize,
+    pub elapsed: Duration,
+    /// Bytes per second, smoothed over the recent reports
+    pub rate: f64,
+    /// Not known until the first bytes arrive
+    pub eta: Option<Duration>,
+    /// Set for the directory archives only
+    pub file: Option<FileProgress>,
+}
+
+/// Position in the directory archive, counted as the files are packed or unpacked
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileProgress {
+    /// Path of the current file inside of the archive
+    pub name: String,
+    pub done: usize,
+    /// Older peers don't send the number of files
+    pub total: Option<usize>,
+}
+
+impl fmt::Display for FileProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.total {
+            Some(total) => write!(f, "{} of {} files", self.done, total)?,
+            None => write!(f, "{} files", self.done)?,
+        }
+        if !self.name.is_empty() {
+            write!(f, " ({})", self.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        if self.total > 0 {
+            (self.counter as f64 / self.total as f64).min(1.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counter = ByteSize(self.counter as u64);
+        let total = ByteSize(self.total as u64);
+        write!(f, "{} of {}", counter, total)?;
+        if let Some(eta) = self.eta {
+            let rate = ByteSize(self.rate as u64);
+            write!(f, ", {}/s, {} left", rate, format_duration(eta))?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, ", {}", file)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the duration as "m:ss", or "h:mm:ss" for the longer ones
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Enumeration)]
 pub enum TransferType {
     File = 0,
     Text = 1,
     Dir = 2,
+    Batch = 4,
 }
 
 #[derive(Debug, Clone)]
 pub enum PeerEvent {
     PeersUpdated(CurrentPeers),
-    WaitingForAnswer,
-    TransferRejected,
-    TransferProgress((usize, usize, Direction)),
-    TransferCompleted,
+    /// Payload name and the other device, sent once before any progress of the transfer
+    TransferStarted(TransferId, String, Peer, Direction),
+    /// Sender hashes the payload before offering it, counts bytes hashed so far
+    TransferPreparing((TransferId, usize, usize)),
+    WaitingForAnswer(TransferId),
+    TransferRejected(TransferId, Rejection),
+    TransferProgress((TransferId, Progress, Direction)),
+    TransferCompleted(TransferId),
+    /// Transfer stopped because of an error, other transfers are not affected
+    TransferFailed(TransferId, String),
+    TransferCancelled(TransferId),
+    TransferCancelledByPeer(TransferId),
+    /// Receiver reported that the sent payload didn't match its digest
+    TransferCorrupted(TransferId),
+    /// Nobody answered the incoming transfer in time, so it was denied
+    AnswerTimedOut(TransferId),
     FileCorrect(String, Payload),
-    FileIncorrect,
-    FileIncoming(String, String, usize, TransferType),
+    /// Names of the files which arrived corrupted
+    FileIncorrect(Vec<String>),
+    /// Last field is the free space in the downloads directory, if it's known
+    FileIncoming(
+        TransferId,
+        String,
+        String,
+        usize,
+        TransferType,
+        usize,
+        Peer,
+        Option<u64>,
+    ),
+    /// Accepted payload has the same name as an existing file, the user decides what to do
+    FileExists(TransferId, String),
+    /// Accepted payload was rejected, because its name is already taken
+    FileSkipped(String),
+    /// Peer doesn't share any protocol version with us
+    IncompatibleVersion(Peer),
     Error(String),
 }
 
+impl PeerEvent {
+    /// Transfer the event belongs to, None for the events of the discovery
+    /// and for the results of the received payloads
+    pub fn transfer_id(&self) -> Option<TransferId> {
+        match self {
+            PeerEvent::TransferStarted(id, ..)
+            | PeerEvent::TransferPreparing((id, _, _))
+            | PeerEvent::WaitingForAnswer(id)
+            | PeerEvent::TransferRejected(id, _)
+            | PeerEvent::TransferProgress((id, _, _))
+            | PeerEvent::TransferCompleted(id)
+            | PeerEvent::TransferFailed(id, _)
+            | PeerEvent::TransferCancelled(id)
+            | PeerEvent::TransferCancelledByPeer(id)
+            | PeerEvent::TransferCorrupted(id)
+            | PeerEvent::AnswerTimedOut(id)
+            | PeerEvent::FileIncoming(id, ..)
+            | PeerEvent::FileExists(id, _) => Some(*id),
+            PeerEvent::PeersUpdated(_)
+            | PeerEvent::FileCorrect(..)
+            | PeerEvent::FileIncorrect(_)
+            | PeerEvent::FileSkipped(_)
+            | PeerEvent::IncompatibleVersion(_)
+            | PeerEvent::Error(_) => None,
+        }
+    }
+}
+
 pub type CurrentPeers = Vec<Peer>;
 
 #[derive(Debug, Eq, Hash, Clone)]
@@ -40,6 +178,35 @@ pub struct Peer {
     pub peer_id: PeerId,
     pub hostname: String,
     pub os: OperatingSystem,
+    pub paired: bool,
+}
+
+impl Peer {
+    /// Peer which was seen in the network, but didn't share its host details yet
+    pub fn new(peer_id: PeerId, address: Multiaddr) -> Peer {
+        Peer {
+            name: peer_id.to_base58(),
+            peer_id,
+            address,
+            hostname: "Not known yet".to_string(),
+            os: OperatingSystem::Unknown,
+            paired: false,
+        }
+    }
+
+    pub fn display_name(&self) -> String {
+        match self.os {
+            OperatingSystem::Unknown => self.name.to_string(),
+            _ => self.hostname.to_string(),
+        }
+    }
+
+    pub fn incompatible_hint(&self) -> String {
+        format!(
+            "{} uses an incompatible Dragit version, update both devices to the latest release",
+            self.display_name()
+        )
+    }
 }
 
 impl PartialEq for Peer {
@@ -48,6 +215,28 @@ impl PartialEq for Peer {
     }
 }
 
+/// Peers known from the discovery. The table is shared with the transfer
+/// behaviour, so incoming transfers can be matched with the sending device.
+#[derive(Debug, Clone, Default)]
+pub struct PeerTable(Arc<Mutex<HashMap<PeerId, Peer>>>);
+
+impl PeerTable {
+    pub fn lock(&self) -> MutexGuard<'_, HashMap<PeerId, Peer>> {
+        // The table holds plain data, so it's fine to use it even after a panic elsewhere
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<Peer> {
+        self.lock().get(peer_id).cloned()
+    }
+
+    /// Returns known peer or a placeholder if discovery didn't see the peer yet
+    pub fn resolve(&self, peer_id: &PeerId) -> Peer {
+        self.get(peer_id)
+            .unwrap_or_else(|| Peer::new(peer_id.clone(), Multiaddr::empty()))
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Enumeration)]
 pub enum OperatingSystem {
     Linux = 0,
@@ -63,6 +252,85 @@ impl fmt::Display for TransferType {
             Self::File => write!(f, "TransferType: File"),
             Self::Text => write!(f, "TransferType: Text"),
             Self::Dir => write!(f, "TransferType: Directory"),
+            Self::Batch => write!(f, "TransferType: Batch"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::commands::TransferId;
+    use crate::p2p::peer::{
+        format_duration, FileProgress, OperatingSystem, Peer, PeerEvent, PeerTable, Progress,
+    };
+    use libp2p::{Multiaddr, PeerId};
+    use std::time::Duration;
+
+    #[test]
+    fn test_resolve_known_peer() {
+        let peers = PeerTable::default();
+        let peer_id = PeerId::random();
+        let mut peer = Peer::new(peer_id.clone(), Multiaddr::empty());
+        peer.hostname = "laptop-anna".to_string();
+        peer.os = OperatingSystem::Linux;
+        peers.lock().insert(peer_id.clone(), peer);
+
+        let result = peers.resolve(&peer_id);
+
+        assert_eq!(result.display_name(), "laptop-anna");
+        assert_eq!(result.os, OperatingSystem::Linux);
+    }
+
+    #[test]
+    fn test_resolve_unknown_peer() {
+        let peers = PeerTable::default();
+        let peer_id = PeerId::random();
+
+        let result = peers.resolve(&peer_id);
+
+        assert_eq!(result.display_name(), peer_id.to_base58());
+        assert_eq!(result.os, OperatingSystem::Unknown);
+    }
+
+    #[test]
+    fn test_transfer_id() {
+        let id = TransferId::generate();
+
+        assert_eq!(PeerEvent::TransferCorrupted(id).transfer_id(), Some(id));
+        assert_eq!(PeerEvent::Error("mdns".to_string()).transfer_id(), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(5)), "0:05");
+        assert_eq!(format_duration(Duration::from_secs(65)), "1:05");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1:02:05");
+    }
+
+    #[test]
+    fn test_progress_text() {
+        let mut progress = Progress {
+            counter: 500,
+            total: 800,
+            elapsed: Duration::from_secs(5),
+            rate: 100.0,
+            eta: None,
+            file: None,
+        };
+        assert_eq!(progress.to_string(), "500 B of 800 B");
+        assert_eq!(progress.fraction(), 0.625);
+
+        progress.eta = Some(Duration::from_secs(3));
+        assert_eq!(progress.to_string(), "500 B of 800 B, 100 B/s, 0:03 left");
+
+        progress.file = Some(FileProgress {
+            name: "photos/cat.jpg".to_string(),
+            done: 2,
+            total: Some(5),
+        });
+        assert_eq!(
+            progress.to_string(),
+            "500 B of 800 B, 100 B/s, 0:03 left, 2 of 5 files (photos/cat.jpg)"
+        );
+    }
+}
@@ -1,45 +1,60 @@
-use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use async_std::channel::{Receiver, Sender};
-use async_std::sync::Mutex;
+use futures::StreamExt;
 
 use libp2p::core::{connection::ConnectionId, ConnectedPoint, Multiaddr, PeerId};
 use libp2p::swarm::{
-    DialError, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, OneShotHandler,
-    OneShotHandlerConfig, PollParameters, SubstreamProtocol,
+    DialError, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters,
 };
 
-use super::protocol::{ProtocolEvent, TransferOut, TransferPayload};
-use crate::p2p::commands::TransferCommand;
-use crate::p2p::peer::PeerEvent;
-use crate::p2p::transfer::file::{FileToSend, Payload};
+use super::handler::TransferHandlerProto;
+use super::protocol::{ProtocolEvent, TransferOut, TransferPayload, Verification};
+use crate::p2p::commands::{ActiveTransfers, PendingAnswers, ReceivingFiles, TransferCommand};
+use crate::p2p::peer::{PeerEvent, PeerTable};
+use crate::p2p::transfer::file::{FileToSend, HashCache, Payload};
+use crate::user_data::CollisionPolicy;
 
-const TIMEOUT: u64 = 600;
-
-type Handler = OneShotHandler<TransferPayload, TransferOut, ProtocolEvent>;
+type Handler = TransferHandlerProto;
 
 pub struct TransferBehaviour {
     pub events: Vec<NetworkBehaviourAction<TransferPayload, Handler>>,
     payloads: Vec<FileToSend>,
     pub sender: Sender<PeerEvent>,
-    receiver: Arc<Mutex<Receiver<TransferCommand>>>,
+    receiver: Receiver<TransferCommand>,
+    answers: PendingAnswers,
+    pub transfers: ActiveTransfers,
+    receiving: ReceivingFiles,
+    pub hashes: HashCache,
     pub target_path: Option<String>,
+    /// Overrides the policy from the config
+    pub collision_policy: Option<CollisionPolicy>,
+    /// Overrides the answer timeout from the config
+    pub answer_timeout: Option<Duration>,
+    peers: PeerTable,
 }
 
 impl TransferBehaviour {
     pub fn new(
         sender: Sender<PeerEvent>,
-        receiver: Arc<Mutex<Receiver<TransferCommand>>>,
+        receiver: Receiver<TransferCommand>,
         target_path: Option<String>,
+        peers: PeerTable,
     ) -> Self {
         TransferBehaviour {
             events: vec![],
             payloads: vec![],
             sender,
             receiver,
+            answers: PendingAnswers::default(),
+            transfers: ActiveTransfers::default(),
+            receiving: ReceivingFiles::default(),
+            hashes: HashCache::default(),
             target_path,
+            collision_policy: None,
+            answer_timeout: None,
+            peers,
         }
     }
 
@@ -53,24 +68,23 @@ impl NetworkBehaviour for TransferBehaviour {
     type OutEvent = TransferPayload;
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
-        let timeout = Duration::from_secs(TIMEOUT);
         let tp = TransferPayload {
             name: "default".to_string(),
             hash: "".to_string(),
+            verification: Verification::Unverified,
             payload: Payload::File(".".to_string()),
             size_bytes: 0,
             sender_queue: self.sender.clone(),
-            receiver: Arc::clone(&self.receiver),
+            answers: self.answers.clone(),
+            transfers: self.transfers.clone(),
+            receiving: self.receiving.clone(),
             target_path: self.target_path.clone(),
+            collision_policy: self.collision_policy,
+            answer_timeout: self.answer_timeout,
+            peer: None,
+            peers: self.peers.clone(),
         };
-        let handler_config = OneShotHandlerConfig {
-            keep_alive_timeout: Duration::from_secs(5),
-            outbound_substream_timeout: timeout,
-            // Default from the library
-            max_dial_negotiated: 8,
-        };
-        let proto = SubstreamProtocol::new(tp, ()).with_timeout(timeout);
-        Self::ConnectionHandler::new(proto, handler_config)
+        Self::ConnectionHandler::new(tp)
     }
 
     fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
@@ -100,26 +114,44 @@ impl NetworkBehaviour for TransferBehaviour {
         warn!("Dial failure: {:?}, {}", peer_id, error);
     }
 
-    fn inject_event(&mut self, _: PeerId, _: ConnectionId, event: ProtocolEvent) {
+    fn inject_event(&mut self, peer_id: PeerId, _: ConnectionId, event: ProtocolEvent) {
         info!("Inject event: {}", event);
         match event {
             ProtocolEvent::Received(data) => self
                 .events
                 .push(NetworkBehaviourAction::GenerateEvent(data)),
             ProtocolEvent::Sent => return,
+            ProtocolEvent::Incompatible => {
+                let peer = self.peers.resolve(&peer_id);
+                if let Err(e) = self.sender.try_send(PeerEvent::IncompatibleVersion(peer)) {
+                    error!("Failed to notify the frontend: {:?}", e);
+                }
+            }
         };
     }
 
     fn poll(
         &mut self,
-        _: &mut Context,
+        cx: &mut Context,
         _: &mut impl PollParameters,
     ) -> Poll<NetworkBehaviourAction<TransferPayload, Handler>> {
+        // Answers from the frontend go to the handlers waiting for them
+        while let Poll::Ready(Some(command)) = self.receiver.poll_next_unpin(cx) {
+            match command {
+                // Transfer which didn't start yet is cancelled by its answer
+                TransferCommand::Cancel(id) if self.transfers.cancel(&id) => (),
+                command => self.answers.route(command),
+            }
+        }
+
         if let Some(file) = self.payloads.pop() {
             let peer_id = file.peer.clone();
             let transfer = TransferOut {
                 file,
                 sender_queue: self.sender.clone(),
+                transfers: self.transfers.clone(),
+                hashes: self.hashes.clone(),
+                peers: self.peers.clone(),
             };
 
             let event = NetworkBehaviourAction::NotifyHandler {
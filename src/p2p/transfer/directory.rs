@@ -1,86 +1,161 @@
-use std::convert::TryFrom;
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result as IOResult};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use async_std::channel::Sender;
-use async_std::fs::{create_dir, create_dir_all};
-use async_std::io::BufReader;
+use async_std::fs::{create_dir, create_dir_all, symlink_metadata, File as AsyncFile};
 use async_std::task::{spawn, JoinHandle};
 use async_zip::error::ZipError;
 use async_zip::read::stream::ZipFileReader;
 use async_zip::write::ZipFileWriter;
 use async_zip::Compression;
 use async_zip::ZipEntryBuilder;
-use futures::AsyncRead;
+use futures::{AsyncRead, AsyncWrite};
+use prost::Message;
 use tokio::fs::File;
-use tokio::io::{copy_buf, duplex, BufReader as TokioBufReader, DuplexStream};
-use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_util::compat::{
+    Compat, FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt, TokioAsyncReadCompatExt,
+};
 use walkdir::WalkDir;
 
+use crate::p2p::commands::TransferId;
 use crate::p2p::peer::Direction;
-use crate::p2p::util::{notify_progress, TSocketAlias};
+use crate::p2p::transfer::metadata::{HashAlgorithm, Hasher, Metadata};
+use crate::p2p::transfer::proto::{Manifest, ManifestEntry};
+use crate::p2p::util::{notify_progress, ProgressTracker};
 use crate::p2p::PeerEvent;
+use crate::user_data::{resolve_collision, CollisionPolicy};
 
 const ZIP_BUFFER_SIZE: usize = 1024 * 64;
 
+/// Last entry of the archive, it lists the digests of all the files
+pub const MANIFEST_NAME: &str = ".dragit-manifest";
+
+/// Manifest is kept in memory, this is enough for hundreds of thousands of files
+const MAX_MANIFEST_SIZE: usize = 32 * 1024 * 1024;
+
 // Slower than Stored, but more doesn't cause any CRC32 check errors
 const DEFAULT_COMPRESSION: Compression = Compression::Deflate;
 
 pub type MaybeTaskHandle = Option<JoinHandle<Result<(), Error>>>;
 
+/// Progress of the directory transfer. It is counted in the bytes of the packed files,
+/// not of the compressed stream, so both sides report the same numbers.
+pub struct ArchiveProgress {
+    sender_queue: Sender<PeerEvent>,
+    id: TransferId,
+    direction: Direction,
+    tracker: ProgressTracker,
+    /// Set when a report didn't fit in the queue, so the next one is not skipped
+    dropped: bool,
+}
+
+impl ArchiveProgress {
+    /// Older peers don't send the number of `files`
+    pub fn new(
+        sender_queue: Sender<PeerEvent>,
+        id: TransferId,
+        direction: Direction,
+        size: usize,
+        files: Option<usize>,
+    ) -> Self {
+        ArchiveProgress {
+            sender_queue,
+            id,
+            direction,
+            tracker: ProgressTracker::new(0, size).with_files(files),
+            dropped: false,
+        }
+    }
+
+    async fn advance(&mut self, bytes: usize) {
+        if let Some(progress) = self.tracker.advance(bytes) {
+            notify_progress(&self.sender_queue, self.id, progress, &self.direction).await;
+        }
+    }
+
+    /// Same as `advance`, for the writers which can't wait for the queue.
+    /// A report is dropped if the queue is full, then the end of the file is reported
+    /// right away with `finish_file`.
+    fn advance_now(&mut self, bytes: usize) {
+        if let Some(progress) = self.tracker.advance(bytes) {
+            let event = PeerEvent::TransferProgress((self.id, progress, self.direction.clone()));
+            if let Err(e) = self.sender_queue.try_send(event) {
+                debug!("Progress report dropped: {}", e);
+                self.dropped = true;
+            }
+        }
+    }
+
+    fn start_file(&mut self, name: &str) {
+        self.tracker.start_file(name);
+    }
+
+    /// Many small files carry few bytes, so the count of files is reported on its own
+    async fn finish_file(&mut self) {
+        self.tracker.finish_file();
+        if self.dropped {
+            self.report().await;
+        } else {
+            self.advance(0).await;
+        }
+    }
+
+    /// Waits for the queue, so the first and the last report always get through
+    async fn report(&mut self) {
+        self.dropped = false;
+        let progress = self.tracker.report();
+        notify_progress(&self.sender_queue, self.id, progress, &self.direction).await;
+    }
+}
+
 pub struct ZipStream {
     reader: Compat<DuplexStream>,
     task_handle: MaybeTaskHandle,
 }
 
 impl ZipStream {
-    pub fn new(source_path: String) -> ZipStream {
+    pub fn new(
+        source_path: String,
+        manifest: Option<HashAlgorithm>,
+        progress: ArchiveProgress,
+    ) -> ZipStream {
+        Self::new_batch(vec![source_path], manifest, progress)
+    }
+
+    /// Archive with many files and directories, each of them placed
+    /// at the top level, like they were dropped. Files are hashed while they are
+    /// zipped and the archive ends with the manifest if the algorithm is given.
+    /// The progress follows the files as they are packed.
+    pub fn new_batch(
+        source_paths: Vec<String>,
+        manifest: Option<HashAlgorithm>,
+        mut progress: ArchiveProgress,
+    ) -> ZipStream {
         let (reader, mut writer) = duplex(ZIP_BUFFER_SIZE);
 
         let task_handle = spawn(async move {
             let mut zip = ZipFileWriter::new(&mut writer);
-            let base_path = Path::new(&source_path).parent();
-
-            for entry in WalkDir::new(&source_path) {
-                let entry = entry?;
-                let file_path = entry.path();
-                debug!("{:?}", file_path);
-
-                if !file_path.exists() {
-                    continue;
-                }
-
-                let rel_path = match base_path {
-                    Some(base) => file_path
-                        .strip_prefix(base)
-                        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?,
-                    None => file_path,
-                };
-                let path_string = rel_path
-                    .to_str()
-                    .unwrap_or(&rel_path.to_string_lossy())
-                    .to_owned();
-                debug!("{:?}", rel_path);
-
-                // Only files and empty directories are supported for now. Symlinks are ignored.
-                if file_path.is_file() {
-                    if file_path.metadata()?.len() > 0 {
-                        debug!("Writing file: {}", path_string);
-                        Self::write_file(&mut zip, path_string, &file_path).await?;
-                    } else {
-                        debug!("Writing empty file: {}", path_string);
-                        Self::write_empty_file(&mut zip, path_string).await?;
-                    }
-                } else {
-                    if file_path.read_dir()?.next().is_none() {
-                        debug!("Writing empty directory: {}", path_string);
-                        Self::write_empty_dir(&mut zip, path_string).await?;
-                    }
-                }
+            let mut entries: Vec<ManifestEntry> = vec![];
+            progress.report().await;
+            for source_path in source_paths {
+                Self::write_tree(
+                    &mut zip,
+                    &source_path,
+                    manifest,
+                    &mut entries,
+                    &mut progress,
+                )
+                .await?;
+            }
+            if manifest.is_some() {
+                Self::write_manifest(&mut zip, entries).await?;
             }
             zip.close().await.map_err(|err| zip_error(err))?;
+            progress.report().await;
             Ok::<(), Error>(())
         });
         let compat = reader.compat();
@@ -90,6 +165,67 @@ impl ZipStream {
         }
     }
 
+    async fn write_tree(
+        zip: &mut ZipFileWriter<&mut DuplexStream>,
+        source_path: &str,
+        manifest: Option<HashAlgorithm>,
+        entries: &mut Vec<ManifestEntry>,
+        progress: &mut ArchiveProgress,
+    ) -> Result<(), Error> {
+        let base_path = Path::new(source_path).parent();
+
+        for entry in WalkDir::new(source_path) {
+            let entry = entry?;
+            let file_path = entry.path();
+            debug!("{:?}", file_path);
+
+            if !file_path.exists() {
+                continue;
+            }
+
+            let rel_path = match base_path {
+                Some(base) => file_path
+                    .strip_prefix(base)
+                    .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?,
+                None => file_path,
+            };
+            let path_string = rel_path
+                .to_str()
+                .unwrap_or(&rel_path.to_string_lossy())
+                .to_owned();
+            debug!("{:?}", rel_path);
+
+            // Only files and empty directories are supported for now. Symlinks are ignored.
+            if file_path.is_file() {
+                let mut hasher = manifest.map(Hasher::new);
+                progress.start_file(&path_string);
+                let size = if file_path.metadata()?.len() > 0 {
+                    debug!("Writing file: {}", path_string);
+                    let hasher = hasher.as_mut();
+                    Self::write_file(zip, path_string.clone(), &file_path, hasher, progress).await?
+                } else {
+                    debug!("Writing empty file: {}", path_string);
+                    Self::write_empty_file(zip, path_string.clone()).await?;
+                    0
+                };
+                progress.finish_file().await;
+                if let Some(hasher) = hasher {
+                    entries.push(ManifestEntry {
+                        path: path_string,
+                        size,
+                        hash: hasher.finalize(),
+                    });
+                }
+            } else {
+                if file_path.read_dir()?.next().is_none() {
+                    debug!("Writing empty directory: {}", path_string);
+                    Self::write_empty_dir(zip, path_string).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn write_empty_dir(
         zip: &mut ZipFileWriter<&mut DuplexStream>,
         rel_path: String,
@@ -120,11 +256,14 @@ impl ZipStream {
         Ok(())
     }
 
+    /// Returns the number of bytes written
     async fn write_file(
         zip: &mut ZipFileWriter<&mut DuplexStream>,
         rel_path: String,
         file_path: &Path,
-    ) -> Result<(), Error> {
+        mut hasher: Option<&mut Hasher>,
+        progress: &mut ArchiveProgress,
+    ) -> Result<u64, Error> {
         let opts = ZipEntryBuilder::new(rel_path, DEFAULT_COMPRESSION);
 
         let mut entry_writer = zip
@@ -133,9 +272,36 @@ impl ZipStream {
             .map_err(|err| zip_error(err))?;
 
         let mut file = File::open(&file_path).await?;
-        let mut buf_reader = TokioBufReader::with_capacity(ZIP_BUFFER_SIZE, &mut file);
-        copy_buf(&mut buf_reader, &mut entry_writer).await?;
+        let mut buff = vec![0u8; ZIP_BUFFER_SIZE];
+        let mut size: u64 = 0;
+        loop {
+            let n = file.read(&mut buff).await?;
+            if n == 0 {
+                break;
+            }
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&buff[..n]);
+            }
+            entry_writer.write_all(&buff[..n]).await?;
+            size += n as u64;
+            progress.advance(n).await;
+        }
         entry_writer.close().await.map_err(|err| zip_error(err))?;
+        Ok(size)
+    }
+
+    async fn write_manifest(
+        zip: &mut ZipFileWriter<&mut DuplexStream>,
+        entries: Vec<ManifestEntry>,
+    ) -> Result<(), Error> {
+        let manifest = Manifest { entries };
+        let mut buf = Vec::with_capacity(manifest.encoded_len());
+        manifest.encode(&mut buf)?;
+
+        let opts = ZipEntryBuilder::new(MANIFEST_NAME.to_string(), DEFAULT_COMPRESSION);
+        zip.write_entry_whole(opts, &buf)
+            .await
+            .map_err(|err| zip_error(err))?;
         Ok(())
     }
 
@@ -157,6 +323,140 @@ impl AsyncRead for ZipStream {
     }
 }
 
+/// Computes the digest of the data written through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Option<Hasher>,
+}
+
+impl<W> HashingWriter<W> {
+    fn new(inner: W, algorithm: Option<HashAlgorithm>) -> Self {
+        HashingWriter {
+            inner,
+            hasher: algorithm.map(Hasher::new),
+        }
+    }
+
+    fn finalize(self) -> Option<String> {
+        self.hasher.map(|hasher| hasher.finalize())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IOResult<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(&buf[..n]);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Collects the data in memory, fails once it grows over the limit
+struct LimitedBuffer {
+    data: Vec<u8>,
+    limit: usize,
+}
+
+impl LimitedBuffer {
+    fn new(limit: usize) -> Self {
+        LimitedBuffer {
+            data: vec![],
+            limit,
+        }
+    }
+}
+
+impl AsyncWrite for LimitedBuffer {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IOResult<usize>> {
+        if self.data.len() + buf.len() > self.limit {
+            let error = Error::new(ErrorKind::InvalidData, "Directory manifest is too large");
+            return Poll::Ready(Err(error));
+        }
+        self.data.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Counts the bytes of the extracted file as they are written
+struct ProgressWriter<'a, W> {
+    inner: W,
+    progress: &'a mut ArchiveProgress,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ProgressWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IOResult<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.progress.advance_now(n);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Compares the extracted files with the manifest, returns paths of the files which don't match.
+/// Files missing in the manifest are reported as well, because they couldn't be verified.
+fn verify_manifest(manifest: &Manifest, received: &HashMap<String, (u64, String)>) -> Vec<String> {
+    let expected: HashMap<String, &ManifestEntry> = manifest
+        .entries
+        .iter()
+        .map(|entry| (normalize_zip_path(&entry.path), entry))
+        .collect();
+
+    let mut corrupted: Vec<String> = vec![];
+    for (path, entry) in expected.iter() {
+        match received.get(path) {
+            Some((size, hash)) if *size == entry.size && *hash == entry.hash => (),
+            _ => corrupted.push(path.to_string()),
+        }
+    }
+    for path in received.keys() {
+        if !expected.contains_key(path) {
+            corrupted.push(path.to_string());
+        }
+    }
+    corrupted.sort();
+    corrupted
+}
+
 fn zip_error(err: ZipError) -> Error {
     Error::new(ErrorKind::Other, format!("Zip error: {}", err.to_string()))
 }
@@ -177,65 +477,477 @@ fn normalize_zip_path(path_name: &str) -> String {
     path_name.to_string()
 }
 
+fn unsafe_path_error(entry_path: &str) -> Error {
+    Error::new(
+        ErrorKind::PermissionDenied,
+        format!(
+            "Archive entry {} points outside of the target directory",
+            entry_path
+        ),
+    )
+}
+
+/// Resolves the entry inside of `base_path`. Absolute paths and `..` would let the peer
+/// write anywhere, so such entries are rejected together with the whole archive.
+fn safe_entry_path(base_path: &Path, entry_path: &str) -> Result<PathBuf, Error> {
+    let relative = Path::new(entry_path);
+    let is_relative = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if entry_path.is_empty() || !is_relative {
+        return Err(unsafe_path_error(entry_path));
+    }
+    Ok(base_path.join(relative))
+}
+
+/// Symlinks which already exist in the target directory would redirect the entry elsewhere
+async fn check_symlinks(base_path: &Path, relative_path: &Path) -> Result<(), Error> {
+    let mut current = base_path.to_path_buf();
+    for component in relative_path.components() {
+        current.push(component);
+        if let Ok(meta) = symlink_metadata(&current).await {
+            if meta.file_type().is_symlink() {
+                return Err(unsafe_path_error(&relative_path.to_string_lossy()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks the zip stream into `target_dir`, which is the staging directory of the transfer.
+/// Returns the number of bytes, top-level paths and the files which failed the manifest check.
 pub async fn unzip_stream(
-    target_path: String,
-    buf_reader: BufReader<impl TSocketAlias + 'static>,
-    sender_queue: Sender<PeerEvent>,
-    size: usize,
-    direction: Direction,
-) -> Result<JoinHandle<Result<usize, Error>>, Error> {
-    let mut compat_reader = buf_reader.compat();
-
-    let task = spawn(async move {
-        let base_path = Path::new(&target_path)
-            .parent()
-            .unwrap_or(Path::new(&target_path));
-        let mut zip = ZipFileReader::new(&mut compat_reader);
-        let mut counter: usize = 0;
-        while !zip.finished() {
-            if let Some(reader) = zip.entry_reader().await.map_err(|err| zip_error(err))? {
-                let entry = reader.entry();
-                let path = base_path.join(normalize_zip_path(entry.filename()));
-                if let Some(parent) = path.parent() {
-                    create_dir_all(parent).await?;
+    target_dir: String,
+    reader: impl AsyncRead + Unpin,
+    meta: &Metadata,
+    sender_queue: &Sender<PeerEvent>,
+    id: TransferId,
+) -> Result<(usize, Vec<String>, Vec<String>), Error> {
+    let size = meta.size;
+    let algorithm = meta.manifest_algorithm();
+
+    // Runs on the connection's executor, so the files are written without the tokio runtime
+    let mut compat_reader = reader.compat();
+
+    let base_path = Path::new(&target_dir);
+    let mut zip = ZipFileReader::new(&mut compat_reader);
+    let files = Some(meta.files).filter(|files| *files > 0);
+    let direction = Direction::Incoming;
+    let mut progress = ArchiveProgress::new(sender_queue.clone(), id, direction, size, files);
+    progress.report().await;
+    // Top-level files and directories, there are many of them in a batch
+    let mut roots: Vec<String> = vec![];
+    // Size and digest of each extracted file
+    let mut received: HashMap<String, (u64, String)> = HashMap::new();
+    let mut manifest: Option<Manifest> = None;
+    while !zip.finished() {
+        if let Some(reader) = zip.entry_reader().await.map_err(|err| zip_error(err))? {
+            let entry = reader.entry();
+            let entry_path = normalize_zip_path(entry.filename());
+            if algorithm.is_some() && entry_path == MANIFEST_NAME {
+                let mut buffer = LimitedBuffer::new(MAX_MANIFEST_SIZE).compat_write();
+                reader
+                    .copy_to_end_crc(&mut buffer, ZIP_BUFFER_SIZE)
+                    .await
+                    .map_err(|err| zip_error(err))?;
+                manifest = Some(Manifest::decode(&buffer.into_inner().data[..])?);
+                continue;
+            }
+            let path = safe_entry_path(base_path, &entry_path)?;
+            check_symlinks(base_path, Path::new(&entry_path)).await?;
+
+            if let Some(root) = Path::new(&entry_path).components().next() {
+                let root_string = base_path.join(root).to_string_lossy().to_string();
+                if !roots.contains(&root_string) {
+                    roots.push(root_string);
                 }
-                debug!("Unzip: {:?}", path.to_string_lossy());
+            }
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent).await?;
+            }
+            debug!("Unzip: {:?}", path.to_string_lossy());
 
-                if is_zip_dir(&path) {
-                    debug!("Creating dir {:?}", path);
-                    if let Err(e) = create_dir(path).await {
-                        warn!("Could not create directory: {:?}", e);
-                    };
-                } else {
-                    debug!("Creating file {:?}", path);
-                    let mut file = File::create(&path).await?;
-                    reader
-                        .copy_to_end_crc(&mut file, ZIP_BUFFER_SIZE)
-                        .await
-                        .map_err(|err| zip_error(err))?;
-
-                    let meta = file.metadata().await?;
-                    let file_size = usize::try_from(meta.len())
-                        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
-                    counter += file_size;
-
-                    // Limit progress events, because they seem to be to be inefficient at gtk level
-                    if (file_size as f32 / size as f32) > 0.01 {
-                        notify_progress(&sender_queue, counter, size, &direction).await;
-                    }
+            if is_zip_dir(&path) {
+                debug!("Creating dir {:?}", path);
+                if let Err(e) = create_dir(path).await {
+                    warn!("Could not create directory: {:?}", e);
+                };
+            } else {
+                debug!("Creating file {:?}", path);
+                progress.start_file(&entry_path);
+                let file = AsyncFile::create(&path).await?;
+                let mut file = ProgressWriter {
+                    inner: HashingWriter::new(file, algorithm),
+                    progress: &mut progress,
                 }
+                .compat_write();
+                reader
+                    .copy_to_end_crc(&mut file, ZIP_BUFFER_SIZE)
+                    .await
+                    .map_err(|err| zip_error(err))?;
+                file.flush().await?;
+
+                let file = file.into_inner().inner;
+                let file_meta = file.inner.metadata().await?;
+
+                if let Some(hash) = file.finalize() {
+                    received.insert(entry_path, (file_meta.len(), hash));
+                }
+                progress.finish_file().await;
             }
         }
-        Ok::<usize, Error>(counter)
-    });
-    Ok(task)
+    }
+
+    let corrupted = match (algorithm, manifest) {
+        (Some(_), Some(manifest)) => verify_manifest(&manifest, &received),
+        (Some(_), None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Directory manifest is missing",
+            ))
+        }
+        (None, _) => vec![],
+    };
+    progress.report().await;
+    Ok((progress.tracker.counter(), roots, corrupted))
+}
+
+/// Moves the extracted item into place. Directories are merged with the existing ones
+/// and files replace the existing files, anything else is kept under a new name.
+fn merge_into(source: &Path, target: &Path) -> Result<(), Error> {
+    match std::fs::symlink_metadata(target) {
+        Ok(existing) if existing.is_dir() && source.is_dir() => {
+            for entry in std::fs::read_dir(source)? {
+                let entry = entry?;
+                merge_into(&entry.path(), &target.join(entry.file_name()))?;
+            }
+            Ok(())
+        }
+        Ok(existing) if existing.is_file() && source.is_file() => std::fs::rename(source, target),
+        Ok(_) => {
+            let target = resolve_collision(&target.to_string_lossy(), CollisionPolicy::Rename)
+                .unwrap_or_else(|| target.to_string_lossy().to_string());
+            std::fs::rename(source, target)
+        }
+        Err(_) => std::fs::rename(source, target),
+    }
+}
+
+/// Moves the top-level items out of the staging directory into `target_dir`, once they
+/// are complete. Returns the final paths, items skipped because of the collision are left out.
+pub fn move_extracted(
+    roots: &[String],
+    target_dir: &Path,
+    collisions: CollisionPolicy,
+) -> Result<Vec<String>, Error> {
+    let mut moved: Vec<String> = vec![];
+    for root in roots {
+        let source = Path::new(root);
+        let name = source
+            .file_name()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid archive entry"))?;
+        let target = target_dir.join(name).to_string_lossy().to_string();
+        match resolve_collision(&target, collisions) {
+            Some(target) => {
+                merge_into(source, Path::new(&target))?;
+                moved.push(target);
+            }
+            None => info!("Skipping {}, it already exists", target),
+        }
+    }
+    Ok(moved)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::p2p::transfer::directory::{is_zip_dir, normalize_zip_path};
+    use crate::p2p::commands::TransferId;
+    use crate::p2p::transfer::directory::{
+        check_symlinks, is_zip_dir, move_extracted, normalize_zip_path, safe_entry_path,
+        unzip_stream, verify_manifest, HashingWriter, LimitedBuffer,
+    };
+    use crate::p2p::transfer::metadata::{HashAlgorithm, Metadata};
+    use crate::p2p::transfer::proto::{Manifest, ManifestEntry};
+    use crate::p2p::TransferType;
+    use crate::user_data::CollisionPolicy;
+    use async_std::channel::bounded;
+    use async_zip::write::ZipFileWriter;
+    use async_zip::{Compression, ZipEntryBuilder};
+    use futures::executor::block_on;
+    use futures::io::{AsyncWriteExt, Cursor};
+    use std::collections::HashMap;
+    use std::io::Error;
     use std::path::Path;
 
+    fn manifest_entry(path: &str, size: u64, hash: &str) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            size,
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_verify_manifest_correct() {
+        let manifest = Manifest {
+            entries: vec![manifest_entry("dir/a.txt", 3, "aaa")],
+        };
+        let mut received = HashMap::new();
+        received.insert("dir/a.txt".to_string(), (3, "aaa".to_string()));
+
+        assert!(verify_manifest(&manifest, &received).is_empty());
+    }
+
+    #[test]
+    fn test_verify_manifest_reports_failed_files() {
+        let manifest = Manifest {
+            entries: vec![
+                manifest_entry("dir/a.txt", 3, "aaa"),
+                manifest_entry("dir/b.txt", 3, "bbb"),
+                manifest_entry("dir/missing.txt", 1, "ccc"),
+            ],
+        };
+        let mut received = HashMap::new();
+        received.insert("dir/a.txt".to_string(), (3, "aaa".to_string()));
+        received.insert("dir/b.txt".to_string(), (3, "xxx".to_string()));
+        received.insert("dir/extra.txt".to_string(), (1, "ddd".to_string()));
+
+        let result = verify_manifest(&manifest, &received);
+
+        assert_eq!(
+            result,
+            vec!["dir/b.txt", "dir/extra.txt", "dir/missing.txt"]
+        );
+    }
+
+    #[test]
+    fn test_hashing_writer() {
+        let mut writer = HashingWriter::new(Cursor::new(Vec::new()), Some(HashAlgorithm::Sha256));
+        block_on(writer.write_all(b"I'll fly to device!")).unwrap();
+
+        assert_eq!(writer.inner.get_ref().len(), 19);
+        assert_eq!(
+            writer.finalize(),
+            Some("8b8de9ed7122ddb61b6481c46c009e757778b41d2986ea43d97415404c83dacd".to_string())
+        );
+    }
+
+    #[test]
+    fn test_limited_buffer() {
+        let mut buffer = LimitedBuffer::new(8);
+        block_on(buffer.write_all(b"manifest")).unwrap();
+
+        assert_eq!(buffer.data, b"manifest");
+        assert!(block_on(buffer.write_all(b"!")).is_err());
+        assert_eq!(buffer.data.len(), 8);
+    }
+
+    /// Archive with the given entries, as a malicious peer could send it
+    fn crafted_zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![];
+        block_on(async {
+            let mut zip = ZipFileWriter::new(&mut data);
+            for (name, contents) in entries {
+                let opts = ZipEntryBuilder::new(name.to_string(), Compression::Stored);
+                zip.write_entry_whole(opts, contents).await.unwrap();
+            }
+            zip.close().await.unwrap();
+        });
+        data
+    }
+
+    /// Unpacks the archive into the `staging` directory of `base`
+    fn unzip_crafted(base: &Path, entries: &[(&str, &[u8])]) -> Result<Vec<String>, Error> {
+        let staging = base.join("staging");
+        std::fs::create_dir_all(&staging).unwrap();
+        let meta = Metadata {
+            name: "dir".to_string(),
+            hash: "directory".to_string(),
+            size: entries.iter().map(|(_, contents)| contents.len()).sum(),
+            transfer_type: TransferType::Dir,
+            count: 1,
+            resumable: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            manifest: false,
+            files: entries.len(),
+            confirm: false,
+        };
+        let (sender, _receiver) = bounded(1024);
+        let reader = Cursor::new(crafted_zip(entries));
+        let target = staging.to_string_lossy().to_string();
+        let result = block_on(unzip_stream(
+            target,
+            reader,
+            &meta,
+            &sender,
+            TransferId::generate(),
+        ));
+        result.map(|(_, roots, _)| roots)
+    }
+
+    #[test]
+    fn test_unzip_crafted_archive() {
+        let base = tempfile::tempdir().unwrap();
+
+        let roots = unzip_crafted(base.path(), &[("dir/file.txt", b"safe")]).unwrap();
+
+        assert_eq!(roots.len(), 1);
+        let extracted = base.path().join("staging").join("dir").join("file.txt");
+        assert_eq!(std::fs::read(extracted).unwrap(), b"safe");
+    }
+
+    #[test]
+    fn test_unzip_rejects_parent_entries() {
+        let base = tempfile::tempdir().unwrap();
+        let entries: &[(&str, &[u8])] = &[("dir/ok.txt", b"ok"), ("../evil.txt", b"evil")];
+
+        assert!(unzip_crafted(base.path(), entries).is_err());
+        assert!(!base.path().join("evil.txt").exists());
+
+        let entries: &[(&str, &[u8])] = &[("dir/../../evil.txt", b"evil")];
+        assert!(unzip_crafted(base.path(), entries).is_err());
+        assert!(!base.path().join("evil.txt").exists());
+    }
+
+    #[test]
+    fn test_unzip_rejects_absolute_entries() {
+        let base = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let evil = outside.path().join("evil.txt");
+        let evil_name = evil.to_string_lossy().to_string();
+
+        assert!(unzip_crafted(base.path(), &[(&evil_name, b"evil")]).is_err());
+        assert!(!evil.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unzip_rejects_entries_through_symlinks() {
+        let base = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let staging = base.path().join("staging");
+        std::fs::create_dir_all(&staging).unwrap();
+        std::os::unix::fs::symlink(outside.path(), staging.join("link")).unwrap();
+
+        assert!(unzip_crafted(base.path(), &[("link/evil.txt", b"evil")]).is_err());
+        assert!(!outside.path().join("evil.txt").exists());
+    }
+
+    #[test]
+    fn test_unzip_keeps_symlink_entries_as_files() {
+        let base = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let target = outside.path().to_string_lossy().to_string();
+        // Link and a file behind it, the link entry is written as a plain file with its target
+        let entries: &[(&str, &[u8])] = &[("link", target.as_bytes()), ("link/evil.txt", b"evil")];
+
+        assert!(unzip_crafted(base.path(), entries).is_err());
+        assert!(!outside.path().join("evil.txt").exists());
+        let link = base.path().join("staging").join("link");
+        assert!(!link.symlink_metadata().unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn test_safe_entry_path() {
+        let base = Path::new("downloads");
+
+        assert_eq!(
+            safe_entry_path(base, "dir/./file.txt").unwrap(),
+            base.join("dir/./file.txt")
+        );
+        assert!(safe_entry_path(base, "../evil.txt").is_err());
+        assert!(safe_entry_path(base, "dir/../../evil.txt").is_err());
+        assert!(safe_entry_path(base, "/tmp/evil.txt").is_err());
+        assert!(safe_entry_path(base, "").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_symlinks() {
+        let base = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), base.path().join("link")).unwrap();
+        std::fs::create_dir(base.path().join("dir")).unwrap();
+
+        let check = |path: &str| block_on(check_symlinks(base.path(), Path::new(path)));
+
+        assert!(check("dir/file.txt").is_ok());
+        assert!(check("new/file.txt").is_ok());
+        assert!(check("link/evil.txt").is_err());
+        assert!(check("link").is_err());
+    }
+
+    /// Staging and target directories, both with "dir/" and "file.txt"
+    fn extracted_with_collisions() -> (tempfile::TempDir, tempfile::TempDir, Vec<String>) {
+        let staging = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        std::fs::create_dir(staging.path().join("dir")).unwrap();
+        std::fs::write(staging.path().join("dir").join("new.txt"), b"new").unwrap();
+        std::fs::write(staging.path().join("file.txt"), b"new").unwrap();
+        std::fs::create_dir(target.path().join("dir")).unwrap();
+        std::fs::write(target.path().join("dir").join("old.txt"), b"old").unwrap();
+        std::fs::write(target.path().join("file.txt"), b"old").unwrap();
+
+        let roots = vec![
+            staging.path().join("dir").to_string_lossy().to_string(),
+            staging
+                .path()
+                .join("file.txt")
+                .to_string_lossy()
+                .to_string(),
+        ];
+        (staging, target, roots)
+    }
+
+    #[test]
+    fn test_move_extracted_renamed() {
+        let (_staging, target, roots) = extracted_with_collisions();
+
+        let moved = move_extracted(&roots, target.path(), CollisionPolicy::Rename).unwrap();
+
+        assert_eq!(
+            moved,
+            vec![
+                target.path().join("dir (1)").to_string_lossy().to_string(),
+                target
+                    .path()
+                    .join("file (1).txt")
+                    .to_string_lossy()
+                    .to_string(),
+            ]
+        );
+        assert!(target.path().join("dir (1)").join("new.txt").exists());
+        assert_eq!(
+            std::fs::read(target.path().join("file.txt")).unwrap(),
+            b"old"
+        );
+    }
+
+    #[test]
+    fn test_move_extracted_overwritten() {
+        let (_staging, target, roots) = extracted_with_collisions();
+
+        let moved = move_extracted(&roots, target.path(), CollisionPolicy::Overwrite).unwrap();
+
+        assert_eq!(moved.len(), 2);
+        assert!(target.path().join("dir").join("old.txt").exists());
+        assert!(target.path().join("dir").join("new.txt").exists());
+        assert_eq!(
+            std::fs::read(target.path().join("file.txt")).unwrap(),
+            b"new"
+        );
+    }
+
+    #[test]
+    fn test_move_extracted_skipped() {
+        let (staging, target, roots) = extracted_with_collisions();
+
+        let moved = move_extracted(&roots, target.path(), CollisionPolicy::Skip).unwrap();
+
+        assert!(moved.is_empty());
+        assert!(!target.path().join("dir").join("new.txt").exists());
+        assert!(staging.path().join("file.txt").exists());
+    }
+
     #[cfg(not(windows))]
     #[test]
     fn test_is_zip_dir_unix() {
@@ -1,16 +1,23 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::fs::{metadata, File};
-use std::io::{self, Read, Write};
-use std::path::Path;
+use std::fs::{self, metadata, File, Metadata};
+use std::io::{self, Read, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
 
 use async_std::fs as asyncfs;
+use async_std::task;
+use futures::AsyncSeekExt;
 use libp2p::core::PeerId;
+use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use walkdir::WalkDir;
 
-use crate::p2p::transfer::directory::{MaybeTaskHandle, ZipStream};
-use crate::p2p::transfer::metadata::hash_contents;
+use crate::p2p::transfer::directory::{ArchiveProgress, MaybeTaskHandle, ZipStream};
+use crate::p2p::transfer::metadata::{hash_contents, HashAlgorithm};
 use crate::p2p::TransferType;
 
 #[derive(Debug, Clone)]
@@ -18,6 +25,7 @@ pub enum Payload {
     Dir(String),
     File(String),
     Text(String),
+    Batch(Vec<String>),
 }
 
 impl Payload {
@@ -32,6 +40,7 @@ impl Payload {
 
                 Ok(Payload::Text(contents))
             }
+            TransferType::Batch => Ok(Payload::Batch(vec![path])),
         }
     }
     pub fn new_for_path(path: String) -> Result<Payload, io::Error> {
@@ -42,6 +51,210 @@ impl Payload {
             Ok(Payload::File(path))
         }
     }
+
+    /// Directories and batches are sent as a zip archive
+    pub fn is_archive(&self) -> bool {
+        matches!(self, Payload::Dir(_) | Payload::Batch(_))
+    }
+
+    /// Number of top-level files and directories in the payload
+    pub fn count(&self) -> usize {
+        match self {
+            Payload::Batch(paths) => paths.len(),
+            _ => 1,
+        }
+    }
+}
+
+/// Identifies the version of the file, which was hashed before
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HashCacheKey {
+    path: String,
+    size: u64,
+    modified: SystemTime,
+    algorithm: HashAlgorithm,
+}
+
+impl HashCacheKey {
+    /// Returns None if the platform doesn't provide the modification time
+    pub fn new(path: &str, meta: &Metadata, algorithm: HashAlgorithm) -> Option<Self> {
+        let modified = meta.modified().ok()?;
+        Some(HashCacheKey {
+            path: path.to_string(),
+            size: meta.len(),
+            modified,
+            algorithm,
+        })
+    }
+}
+
+/// Digests of the least recently used files are dropped above this number
+const MAX_HASH_CACHE_ENTRIES: usize = 1000;
+
+/// Entry of the digest cache as stored on disk
+#[derive(Debug, Serialize, Deserialize)]
+struct HashCacheRecord {
+    path: String,
+    size: u64,
+    modified: SystemTime,
+    algorithm: i32,
+    hash: String,
+    used: SystemTime,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HashCacheFile {
+    #[serde(default)]
+    entries: Vec<HashCacheRecord>,
+}
+
+#[derive(Debug, Clone)]
+struct HashCacheEntry {
+    hash: String,
+    /// Last time the digest was computed or looked up
+    used: SystemTime,
+}
+
+/// Digests of the files sent before, so sending the same file again,
+/// for instance to resume the transfer, doesn't read the whole file first.
+/// When loaded from a file, the digests are kept there between runs.
+#[derive(Debug, Clone, Default)]
+pub struct HashCache {
+    entries: Arc<Mutex<HashMap<HashCacheKey, HashCacheEntry>>>,
+    path: Option<PathBuf>,
+    /// Set while the write of the new entries is scheduled, so they're written only once
+    dirty: Arc<AtomicBool>,
+    /// Writes of the file don't overlap, each one stores the latest entries
+    writing: Arc<Mutex<()>>,
+}
+
+impl HashCache {
+    /// Loads the digests stored in `path`, skipping the ones of the files
+    /// which were changed or removed since. Missing or broken file starts empty.
+    pub fn load(path: PathBuf) -> Self {
+        let stored = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str::<HashCacheFile>(&contents).unwrap_or_else(|e| {
+                warn!("Ignoring broken digest cache {:?}: {:?}", path, e);
+                HashCacheFile::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashCacheFile::default(),
+            Err(e) => {
+                warn!("Could not read digest cache {:?}: {:?}", path, e);
+                HashCacheFile::default()
+            }
+        };
+
+        let mut entries = HashMap::new();
+        for record in stored.entries {
+            let algorithm = match HashAlgorithm::from_i32(record.algorithm) {
+                Some(algorithm) => algorithm,
+                None => continue,
+            };
+            let key = HashCacheKey {
+                path: record.path,
+                size: record.size,
+                modified: record.modified,
+                algorithm,
+            };
+            let current = metadata(&key.path)
+                .ok()
+                .and_then(|meta| HashCacheKey::new(&key.path, &meta, algorithm));
+            if current.as_ref() == Some(&key) {
+                let entry = HashCacheEntry {
+                    hash: record.hash,
+                    used: record.used,
+                };
+                entries.insert(key, entry);
+            }
+        }
+        prune_entries(&mut entries, MAX_HASH_CACHE_ENTRIES);
+
+        HashCache {
+            entries: Arc::new(Mutex::new(entries)),
+            path: Some(path),
+            ..Default::default()
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<HashCacheKey, HashCacheEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Looking the digest up keeps it from being dropped, the file learns it with the next write
+    pub fn get(&self, key: &HashCacheKey) -> Option<String> {
+        let mut entries = self.lock();
+        let entry = entries.get_mut(key)?;
+        entry.used = SystemTime::now();
+        Some(entry.hash.clone())
+    }
+
+    /// The file is written in the background, so the transfer doesn't wait for the disk
+    pub fn insert(&self, key: HashCacheKey, hash: String) {
+        {
+            let mut entries = self.lock();
+            let used = SystemTime::now();
+            entries.insert(key, HashCacheEntry { hash, used });
+            prune_entries(&mut entries, MAX_HASH_CACHE_ENTRIES);
+        }
+
+        // Write which is already scheduled takes the new entry as well
+        if self.path.is_none() || self.dirty.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let cache = self.clone();
+        task::spawn_blocking(move || {
+            if let Err(e) = cache.save() {
+                warn!("Could not store digest cache {:?}: {:?}", cache.path, e);
+            }
+        });
+    }
+
+    /// Writes the current entries to the file, if the cache has one.
+    /// The temporary file is written first, so the cache is never left half-written.
+    pub fn save(&self) -> Result<(), io::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        self.dirty.store(false, Ordering::SeqCst);
+
+        let stored = HashCacheFile {
+            entries: self
+                .lock()
+                .iter()
+                .map(|(key, entry)| HashCacheRecord {
+                    path: key.path.clone(),
+                    size: key.size,
+                    modified: key.modified,
+                    algorithm: key.algorithm as i32,
+                    hash: entry.hash.clone(),
+                    used: entry.used,
+                })
+                .collect(),
+        };
+        let contents = toml::to_string(&stored)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// Drops the least recently used entries above the `limit`
+fn prune_entries(entries: &mut HashMap<HashCacheKey, HashCacheEntry>, limit: usize) {
+    if entries.len() <= limit {
+        return;
+    }
+    let mut by_use: Vec<(SystemTime, HashCacheKey)> = entries
+        .iter()
+        .map(|(key, entry)| (entry.used, key.clone()))
+        .collect();
+    by_use.sort_by_key(|(used, _)| *used);
+    for (_, key) in by_use.into_iter().take(entries.len() - limit) {
+        entries.remove(&key);
+    }
 }
 
 pub enum StreamOption {
@@ -97,13 +310,37 @@ impl FileToSend {
                     transfer_type: TransferType::Text,
                 })
             }
+            Payload::Batch(paths) => {
+                let name = format!("{} items", paths.len());
+                Ok(FileToSend {
+                    name,
+                    payload: Payload::Batch(paths),
+                    peer: peer.to_owned(),
+                    transfer_type: TransferType::Batch,
+                })
+            }
         }
     }
 
-    pub async fn get_file_stream(&self) -> Result<StreamOption, io::Error> {
+    /// Opens the payload for streaming. Files can be continued from the given offset,
+    /// other payloads are always streamed from the beginning. Archives end with
+    /// the manifest if the algorithm is given, their progress is reported by the archive
+    /// as it packs the files.
+    pub async fn get_file_stream(
+        &self,
+        offset: u64,
+        manifest: Option<HashAlgorithm>,
+        progress: ArchiveProgress,
+    ) -> Result<StreamOption, io::Error> {
         match &self.payload {
             Payload::Dir(path) => {
-                let mut zip_stream = ZipStream::new(path.to_owned());
+                let mut zip_stream = ZipStream::new(path.to_owned(), manifest, progress);
+                let handle = zip_stream.take_handle();
+                Ok(StreamOption::Zip(zip_stream, handle))
+            }
+            Payload::Batch(paths) => {
+                // All the files and directories go in one archive, so there is one transfer to accept
+                let mut zip_stream = ZipStream::new_batch(paths.to_owned(), manifest, progress);
                 let handle = zip_stream.take_handle();
                 Ok(StreamOption::Zip(zip_stream, handle))
             }
@@ -111,12 +348,21 @@ impl FileToSend {
                 let file = asyncfs::File::from(Self::create_temp_file(text)?);
                 Ok(StreamOption::File(file))
             }
-            Payload::File(path) => Ok(StreamOption::File(asyncfs::File::open(path).await?)),
+            Payload::File(path) => {
+                let mut file = asyncfs::File::open(path).await?;
+                if offset > 0 {
+                    file.seek(SeekFrom::Start(offset)).await?;
+                }
+                Ok(StreamOption::File(file))
+            }
         }
     }
 
-    pub async fn calculate_hash(&self) -> Result<(String, u64), io::Error> {
-        get_hash_from_payload(&self.payload).await
+    pub async fn calculate_hash(
+        &self,
+        algorithm: HashAlgorithm,
+    ) -> Result<(String, u64, usize), io::Error> {
+        get_hash_from_payload(&self.payload, algorithm).await
     }
 
     /// Creates temporary file from text payload, so this kind of payload
@@ -160,46 +406,78 @@ impl fmt::Display for Payload {
             Self::Dir(path) => write!(f, "DirPayload({})", path),
             Self::File(path) => write!(f, "FilePayload({})", path),
             Self::Text(text) => write!(f, "TextPayload({})", text.len()),
+            Self::Batch(paths) => write!(f, "BatchPayload({} items)", paths.len()),
         }
     }
 }
 
-fn check_directory_size(path: &str) -> Result<u64, io::Error> {
+/// Returns the size and the number of files under the path. Only the file contents
+/// are counted, as this is what the progress of the transfer is measured in.
+fn check_directory_size(path: &str) -> Result<(u64, usize), io::Error> {
     let mut total_size = 0;
+    let mut files = 0;
     for entry in WalkDir::new(path) {
         let entry = entry?;
         match metadata(entry.path()) {
-            Ok(meta) => total_size += meta.len(),
+            Ok(meta) if meta.is_file() => {
+                total_size += meta.len();
+                files += 1;
+            }
+            Ok(_) => {}
             Err(e) => warn!("Can't estimate size of {:?}, {}", entry.path(), e),
         };
     }
-    Ok(total_size)
+    Ok((total_size, files))
 }
 
-pub async fn get_hash_from_payload(payload: &Payload) -> Result<(String, u64), io::Error> {
+/// Returns digest, size and number of files of the payload.
+pub async fn get_hash_from_payload(
+    payload: &Payload,
+    algorithm: HashAlgorithm,
+) -> Result<(String, u64, usize), io::Error> {
     match payload {
         Payload::Dir(path) => {
-            let size = check_directory_size(path)?;
-            // Zip internally maintains the (CRC) hash of the zipped content, no need to calculate the hash here
-            Ok(("directory".to_string(), size))
+            let (size, files) = check_directory_size(path)?;
+            // Files of the directory are hashed one by one while zipping, see the manifest
+            Ok(("directory".to_string(), size, files))
         }
         Payload::File(path) => {
             let file = asyncfs::File::open(&path).await?;
-            let (hash, _) = hash_contents(file).await?;
+            let (hash, _) = hash_contents(file, algorithm).await?;
             let meta = asyncfs::metadata(path).await?;
-            Ok((hash, meta.len()))
+            Ok((hash, meta.len(), 1))
         }
         Payload::Text(text) => {
             let file = asyncfs::File::from(FileToSend::create_temp_file(text)?);
-            let (hash, _) = hash_contents(file).await?;
-            Ok((hash, text.len() as u64))
+            let (hash, _) = hash_contents(file, algorithm).await?;
+            Ok((hash, text.len() as u64, 1))
+        }
+        Payload::Batch(paths) => {
+            let mut size = 0;
+            let mut files = 0;
+            for path in paths {
+                let (path_size, path_files) = check_directory_size(path)?;
+                size += path_size;
+                files += path_files;
+            }
+            // Same as for directories, the manifest takes care of the content checks
+            Ok(("batch".to_string(), size, files))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::p2p::transfer::file::FileToSend;
+    use crate::p2p::transfer::file::{
+        prune_entries, FileToSend, HashCache, HashCacheEntry, HashCacheKey, Payload,
+    };
+    use crate::p2p::transfer::metadata::HashAlgorithm;
+    use crate::p2p::TransferType;
+    use libp2p::PeerId;
+    use std::collections::HashMap;
+    use std::fs::{self, metadata};
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
 
     #[test]
     fn test_extract_name_text() {
@@ -208,4 +486,73 @@ mod tests {
 
         assert_eq!(result, "here  (...)");
     }
+
+    #[test]
+    fn test_batch_payload() {
+        let paths = vec![
+            "tests/data/file.txt".to_string(),
+            "tests/data/test_dir".to_string(),
+        ];
+        let payload = Payload::Batch(paths);
+        let result = FileToSend::new(&PeerId::random(), payload).unwrap();
+
+        assert_eq!(result.name, "2 items");
+        assert_eq!(result.transfer_type, TransferType::Batch);
+        assert_eq!(result.payload.count(), 2);
+    }
+
+    #[test]
+    fn test_hash_cache_key_includes_algorithm() {
+        let path = "tests/data/file.txt";
+        let meta = metadata(path).unwrap();
+        let cache = HashCache::default();
+        let md5 = HashCacheKey::new(path, &meta, HashAlgorithm::Md5).unwrap();
+        let sha256 = HashCacheKey::new(path, &meta, HashAlgorithm::Sha256).unwrap();
+
+        cache.insert(sha256.clone(), "digest".to_string());
+
+        assert_eq!(cache.get(&sha256), Some("digest".to_string()));
+        assert_eq!(cache.get(&md5), None);
+    }
+
+    #[test]
+    fn test_hash_cache_persists() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("file.txt");
+        let cache_path = dir.path().join("hashes.toml");
+        fs::write(&file_path, "contents").unwrap();
+        let path = file_path.to_str().unwrap();
+        let key = HashCacheKey::new(path, &metadata(path).unwrap(), HashAlgorithm::Sha256).unwrap();
+
+        let cache = HashCache::load(cache_path.clone());
+        cache.insert(key.clone(), "digest".to_string());
+        cache.save().unwrap();
+        let cache = HashCache::load(cache_path.clone());
+        assert_eq!(cache.get(&key), Some("digest".to_string()));
+
+        // Changed file doesn't match the stored digest anymore
+        fs::write(&file_path, "other contents").unwrap();
+        let cache = HashCache::load(cache_path);
+        assert_eq!(cache.get(&key), None);
+    }
+
+    #[test]
+    fn test_prune_least_recently_used() {
+        let meta = metadata("tests/data/file.txt").unwrap();
+        let mut entries = HashMap::new();
+        for (i, path) in ["a", "b", "c"].iter().enumerate() {
+            let key = HashCacheKey::new(path, &meta, HashAlgorithm::Sha256).unwrap();
+            let entry = HashCacheEntry {
+                hash: path.to_string(),
+                used: SystemTime::UNIX_EPOCH + Duration::from_secs(i as u64),
+            };
+            entries.insert(key, entry);
+        }
+
+        prune_entries(&mut entries, 2);
+
+        let mut kept: Vec<String> = entries.values().map(|e| e.hash.clone()).collect();
+        kept.sort();
+        assert_eq!(kept, vec!["b".to_string(), "c".to_string()]);
+    }
 }
@@ -0,0 +1,177 @@
+use std::io;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use libp2p::core::{ConnectedPoint, PeerId};
+use libp2p::swarm::handler::{
+    ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, KeepAlive,
+    SubstreamProtocol,
+};
+use libp2p::swarm::{IntoConnectionHandler, NegotiatedSubstream};
+use smallvec::SmallVec;
+
+use super::metadata::Framing;
+use super::protocol::{ProtocolEvent, TransferOut, TransferPayload};
+use crate::p2p::util;
+
+/// Limits only the protocol negotiation, the transfer has its own idle timeout
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the connection stays open after the last transfer
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Transfer = BoxFuture<'static, Result<ProtocolEvent, io::Error>>;
+
+/// Handler prototype, which learns the remote peer once the connection is established.
+/// This way each incoming payload knows who is sending it.
+pub struct TransferHandlerProto {
+    payload: TransferPayload,
+}
+
+impl TransferHandlerProto {
+    pub fn new(payload: TransferPayload) -> Self {
+        TransferHandlerProto { payload }
+    }
+}
+
+impl IntoConnectionHandler for TransferHandlerProto {
+    type Handler = TransferHandler;
+
+    fn into_handler(self, remote_peer_id: &PeerId, _endpoint: &ConnectedPoint) -> Self::Handler {
+        let mut payload = self.payload;
+        payload.peer = Some(remote_peer_id.to_owned());
+
+        TransferHandler::new(payload)
+    }
+
+    fn inbound_protocol(&self) -> TransferPayload {
+        self.payload.clone()
+    }
+}
+
+/// Negotiates the transfer substreams and drives the transfers on them.
+/// Unlike the OneShotHandler, the payload is not streamed inside of the upgrade,
+/// so the substream timeout doesn't cut off the long transfers.
+pub struct TransferHandler {
+    payload: TransferPayload,
+    /// Outgoing transfers waiting for the substream
+    dial_queue: SmallVec<[TransferOut; 4]>,
+    /// Number of outbound substreams being negotiated
+    dial_negotiated: u32,
+    /// Transfers in progress, both incoming and outgoing
+    transfers: FuturesUnordered<Transfer>,
+    /// Events which didn't come from any transfer, like failed negotiation
+    events_out: SmallVec<[ProtocolEvent; 4]>,
+    keep_alive: KeepAlive,
+}
+
+impl TransferHandler {
+    pub fn new(payload: TransferPayload) -> Self {
+        TransferHandler {
+            payload,
+            dial_queue: SmallVec::new(),
+            dial_negotiated: 0,
+            transfers: FuturesUnordered::new(),
+            events_out: SmallVec::new(),
+            keep_alive: KeepAlive::Yes,
+        }
+    }
+}
+
+impl ConnectionHandler for TransferHandler {
+    type InEvent = TransferOut;
+    type OutEvent = ProtocolEvent;
+    type Error = io::Error;
+    type InboundProtocol = TransferPayload;
+    type OutboundProtocol = TransferOut;
+    type OutboundOpenInfo = ();
+    type InboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(self.payload.clone(), ()).with_timeout(NEGOTIATION_TIMEOUT)
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        (payload, socket, framing): (TransferPayload, NegotiatedSubstream, Framing),
+        (): Self::InboundOpenInfo,
+    ) {
+        self.keep_alive = KeepAlive::Yes;
+        self.transfers
+            .push(Box::pin(payload.receive(socket, framing)));
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        (transfer, socket, framing): (TransferOut, NegotiatedSubstream, Framing),
+        (): Self::OutboundOpenInfo,
+    ) {
+        self.dial_negotiated -= 1;
+        self.transfers
+            .push(Box::pin(transfer.send(socket, framing)));
+    }
+
+    fn inject_event(&mut self, transfer: Self::InEvent) {
+        self.keep_alive = KeepAlive::Yes;
+        self.dial_queue.push(transfer);
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        (): Self::OutboundOpenInfo,
+        error: ConnectionHandlerUpgrErr<io::Error>,
+    ) {
+        self.dial_negotiated -= 1;
+        // Failed transfer doesn't affect the other ones, so the connection stays open
+        warn!("Could not open the transfer substream: {:?}", error);
+        if util::is_incompatible(&error) {
+            self.events_out.push(ProtocolEvent::Incompatible);
+        }
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.keep_alive
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::OutEvent,
+            Self::Error,
+        >,
+    > {
+        if !self.events_out.is_empty() {
+            return Poll::Ready(ConnectionHandlerEvent::Custom(self.events_out.remove(0)));
+        }
+
+        if !self.dial_queue.is_empty() {
+            let transfer = self.dial_queue.remove(0);
+            self.dial_negotiated += 1;
+            return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                protocol: SubstreamProtocol::new(transfer, ()).with_timeout(NEGOTIATION_TIMEOUT),
+            });
+        }
+
+        while let Poll::Ready(Some(result)) = self.transfers.poll_next_unpin(cx) {
+            match result {
+                Ok(event) => return Poll::Ready(ConnectionHandlerEvent::Custom(event)),
+                // Both sides already notified the frontend about the failure
+                Err(e) => warn!("Transfer failed: {:?}", e),
+            }
+        }
+
+        if self.transfers.is_empty() && self.dial_queue.is_empty() && self.dial_negotiated == 0 {
+            if let KeepAlive::Yes = self.keep_alive {
+                self.keep_alive = KeepAlive::Until(Instant::now() + KEEP_ALIVE_TIMEOUT);
+            }
+        }
+
+        Poll::Pending
+    }
+}
@@ -1,12 +1,15 @@
 use std::fmt;
-use std::io::{self, Error};
+use std::io::{self, Error, ErrorKind};
 
 use super::proto::Answer as ProtoAnswer;
+pub use super::proto::HashAlgorithm;
 use super::proto::Metadata as ProtoMetadata;
+pub use super::proto::RejectReason;
 use futures::prelude::*;
 use hex;
 use md5::{Digest, Md5};
 use prost::Message;
+use sha2::Sha256;
 
 use crate::p2p::transfer::FileToSend;
 use crate::p2p::util::TSocketAlias;
@@ -15,24 +18,151 @@ use crate::p2p::TransferType;
 pub const ANSWER_SIZE: usize = 2;
 pub const PACKET_SIZE: usize = 1024;
 pub const HASH_BUFFER_SIZE: usize = 1024;
+/// Upper limit for the length-prefixed message, so the peer can't make us allocate anything
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Most file systems allow 255 bytes, the rest is left for the suffixes added on collisions
+pub const MAX_FILE_NAME_LENGTH: usize = 200;
+/// Used when nothing is left from the name sent by the peer
+const FALLBACK_FILE_NAME: &str = "dragit-file";
+/// Device names, which can't be used as file names on Windows, regardless of the extension
+const RESERVED_FILE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+pub const PROTOCOL_LEGACY: &str = "/transfer/1.1";
+pub const PROTOCOL_FRAMED: &str = "/transfer/1.2";
+/// Supported versions, newest first. The dialer proposes them in this order,
+/// so multistream-select settles on the highest version known to both sides.
+pub const PROTOCOL_VERSIONS: [&str; 2] = [PROTOCOL_FRAMED, PROTOCOL_LEGACY];
+
+/// How the messages are delimited on the wire, depends on the negotiated protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Message padded with zeros to `PACKET_SIZE`, used by /transfer/1.1
+    Padded,
+    /// Message preceded by its length as big-endian u32, used since /transfer/1.2
+    LengthPrefixed,
+}
+
+impl Framing {
+    pub fn from_protocol(protocol: &str) -> Framing {
+        match protocol {
+            PROTOCOL_LEGACY => Framing::Padded,
+            _ => Framing::LengthPrefixed,
+        }
+    }
+
+    /// Peers speaking /transfer/1.1 don't read the algorithm from the metadata
+    /// and always verify with MD5
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        match self {
+            Framing::Padded => HashAlgorithm::Md5,
+            Framing::LengthPrefixed => HashAlgorithm::Sha256,
+        }
+    }
+
+    /// Algorithm of the directory manifest. Peers speaking /transfer/1.1
+    /// would extract the manifest as a regular file, so they don't get it.
+    pub fn manifest_algorithm(&self) -> Option<HashAlgorithm> {
+        match self {
+            Framing::Padded => None,
+            Framing::LengthPrefixed => Some(self.hash_algorithm()),
+        }
+    }
+
+    /// Peers speaking /transfer/1.1 don't know the batch type. They would take
+    /// the archive for a regular file and fail to verify it.
+    pub fn supports_batch(&self) -> bool {
+        *self == Framing::LengthPrefixed
+    }
+}
+
+/// Digest computed incrementally, so the data doesn't need to be read again to verify it
+pub enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(state) => state.update(data),
+            Hasher::Sha256(state) => state.update(data),
+        }
+    }
+
+    /// Feeds everything from the reader, returns number of bytes read
+    pub async fn update_from(&mut self, mut reader: impl AsyncRead + Unpin) -> Result<u64, Error> {
+        let mut buffer = [0u8; HASH_BUFFER_SIZE];
+        let mut i: u64 = 0;
+        loop {
+            match reader.read(&mut buffer).await {
+                Ok(n) if n == 0 => {
+                    break;
+                }
+                Ok(n) => {
+                    i += n as u64;
+                    self.update(&buffer[..n]);
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(i)
+    }
+
+    pub fn finalize(self) -> String {
+        let digest = match self {
+            Hasher::Md5(state) => state.finalize().to_vec(),
+            Hasher::Sha256(state) => state.finalize().to_vec(),
+        };
+        hex::encode::<Vec<u8>>(digest)
+    }
+}
 
 pub struct Metadata {
     pub name: String,
     pub hash: String,
     pub size: usize,
     pub transfer_type: TransferType,
+    pub count: usize,
+    pub resumable: bool,
+    pub hash_algorithm: HashAlgorithm,
+    pub manifest: bool,
+    /// Files in the archive, 0 if the sender didn't tell
+    pub files: usize,
+    /// Sender waits for the result of the verification, older versions don't
+    pub confirm: bool,
 }
 
 impl Metadata {
-    pub async fn read(socket: impl TSocketAlias) -> Result<(Self, impl TSocketAlias), io::Error> {
-        let (data, socket) = read_from_socket(socket).await?;
-        let proto = ProtoMetadata::decode(&data[..])?;
+    pub async fn read(
+        socket: impl TSocketAlias,
+        framing: Framing,
+    ) -> Result<(Self, impl TSocketAlias), io::Error> {
+        let (proto, socket) = read_message::<ProtoMetadata>(socket, framing).await?;
 
         let name = proto.name;
         let hash = proto.hash;
         let size = proto.size as usize;
         let transfer_type =
             TransferType::from_i32(proto.transfer_type).unwrap_or(TransferType::File);
+        // Older versions send a single item only, without the count
+        let count = (proto.count as usize).max(1);
+        let resumable = proto.resumable;
+        let manifest = proto.manifest;
+        let files = proto.files as usize;
+        let confirm = proto.confirm;
+        let hash_algorithm = HashAlgorithm::from_i32(proto.hash_algorithm)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Unsupported hash algorithm"))?;
         info!("Read: Name: {}, Hash: {}, Size: {}", name, hash, size);
         Ok((
             Metadata {
@@ -40,142 +170,582 @@ impl Metadata {
                 hash,
                 size,
                 transfer_type,
+                count,
+                resumable,
+                hash_algorithm,
+                manifest,
+                files,
+                confirm,
             },
             socket,
         ))
     }
 
+    /// Hash has to be calculated with the algorithm of the `framing`
     pub async fn write(
         file: &FileToSend,
-        mut socket: impl TSocketAlias,
-    ) -> Result<(usize, impl TSocketAlias), io::Error> {
-        let (hash, size) = file.calculate_hash().await?;
-
+        hash: String,
+        size: usize,
+        files: usize,
+        socket: impl TSocketAlias,
+        framing: Framing,
+    ) -> Result<impl TSocketAlias, io::Error> {
         let proto = ProtoMetadata {
             name: file.name.to_string(),
             hash,
-            size,
+            size: size as u64,
             transfer_type: file.transfer_type as i32,
+            count: file.payload.count() as u32,
+            resumable: true,
+            hash_algorithm: framing.hash_algorithm() as i32,
+            manifest: framing.manifest_algorithm().is_some() && file.payload.is_archive(),
+            files: files as u32,
+            confirm: true,
         };
-        let len = proto.encoded_len();
-        let fill = vec![0; PACKET_SIZE - len];
-        let mut buf = Vec::with_capacity(len);
-        proto.encode(&mut buf)?;
-
-        socket.write(&buf[..len]).await?;
+        write_message(socket, &proto, framing).await
+    }
 
-        // Append null bytes to the stream to transmit the full packet
-        socket.write(&fill).await?;
-        socket.flush().await?;
+    /// Algorithm of the manifest, which ends the directory archive
+    pub fn manifest_algorithm(&self) -> Option<HashAlgorithm> {
+        if self.manifest {
+            Some(self.hash_algorithm)
+        } else {
+            None
+        }
+    }
 
-        Ok((size as usize, socket))
+    /// Files and texts are announced with the digest of the whole payload, which
+    /// also names the partial file, so anything else than hex is refused
+    pub fn has_valid_hash(&self) -> bool {
+        let length = match self.hash_algorithm {
+            HashAlgorithm::Md5 => 32,
+            HashAlgorithm::Sha256 => 64,
+        };
+        match self.transfer_type {
+            TransferType::File | TransferType::Text => {
+                self.hash.len() == length && self.hash.bytes().all(|b| b.is_ascii_hexdigit())
+            }
+            // Contents of the archives are verified with the manifest
+            TransferType::Dir | TransferType::Batch => true,
+        }
     }
 
     /// Produce predictable file name for both file and text payloads.
     /// This is necessary for instance for Windows, which doesn't accept
     /// certain characters in file names (like "\n")
     pub fn get_safe_file_name(&self) -> String {
-        match self.transfer_type {
+        let name = match self.transfer_type {
             TransferType::File => self.name.to_string(),
             TransferType::Dir => self.name.to_string(),
+            TransferType::Batch => self.name.to_string(),
             TransferType::Text => {
                 let mut hasher = Md5::new();
                 hasher.update(self.name.to_string());
                 let result = hasher.finalize();
                 hex::encode::<Vec<u8>>(result.to_vec())
             }
-        }
+        };
+        sanitize_file_name(&name)
+    }
+}
+
+/// Turns the name sent by the remote peer into a plain file name, which stays
+/// in the downloads directory. Only the last path component is kept.
+pub fn sanitize_file_name(name: &str) -> String {
+    let base_name = name
+        .split(|c: char| c == '/' || c == '\\')
+        .filter(|part| !matches!(part.trim(), "" | "." | ".."))
+        .last()
+        .unwrap_or("");
+    let mut name: String = base_name.chars().filter(|c| !c.is_control()).collect();
+    // Windows drops trailing spaces and dots, which could turn the name into ".."
+    name.truncate(name.trim_end_matches(&[' ', '.'][..]).len());
+
+    let stem = name.split('.').next().unwrap_or("");
+    if RESERVED_FILE_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        name.insert(0, '_');
     }
+    let name = truncate_file_name(&name, MAX_FILE_NAME_LENGTH);
+    if name.is_empty() {
+        FALLBACK_FILE_NAME.to_string()
+    } else {
+        name
+    }
+}
+
+/// Shortens the name to `max_length` bytes, keeping the extension if it's reasonably short
+fn truncate_file_name(name: &str, max_length: usize) -> String {
+    if name.len() <= max_length {
+        return name.to_string();
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(index) if index > 0 && name.len() - index <= 16 => name.split_at(index),
+        _ => (name, ""),
+    };
+    let mut end = max_length - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], extension)
 }
 
 impl fmt::Display for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Metadata:\n name: {}\n hash: {}\n size: {}\n type: {}\n",
-            self.name, self.hash, self.size, self.transfer_type
+            "Metadata:\n name: {}\n hash: {}\n size: {}\n type: {}\n count: {}\n",
+            self.name, self.hash, self.size, self.transfer_type, self.count
         )
     }
 }
 
+/// Why the receiver didn't accept the payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub reason: RejectReason,
+    /// Typed by the receiving user, never set by the policies
+    pub note: Option<String>,
+}
+
+impl Rejection {
+    pub fn new(reason: RejectReason) -> Self {
+        Rejection { reason, note: None }
+    }
+
+    pub fn with_note(reason: RejectReason, note: Option<String>) -> Self {
+        let note = note
+            .map(|note| note.trim().to_string())
+            .filter(|note| !note.is_empty());
+        Rejection { reason, note }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            RejectReason::Rejected => "Payload was rejected",
+            RejectReason::NoSpace => "Receiving device doesn't have enough free space",
+            RejectReason::Declined => "Payload was declined",
+            RejectReason::HashMismatch => "Payload doesn't match its hash",
+            RejectReason::TooLarge => "Payload exceeds the size limit of the receiver",
+            RejectReason::NotTrusted => "Receiver doesn't trust this device",
+            RejectReason::Busy => "Receiver is busy, try again later",
+            RejectReason::TimedOut => "Receiver didn't answer in time",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.note {
+            Some(note) => write!(f, "{}: \"{}\"", self.reason, note),
+            None => write!(f, "{}", self.reason),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Answer;
 
 impl Answer {
-    pub async fn read(socket: impl TSocketAlias) -> Result<(bool, impl TSocketAlias), io::Error> {
-        let (data, socket) = read_from_socket(socket).await?;
-        let proto = ProtoAnswer::decode(&data[..])?;
+    /// Returns the decision, the offset the sender should continue from, whether
+    /// the receiver confirms the payload once it's verified and the rejection details,
+    /// meaningful only if the payload was not accepted.
+    /// Only files can be resumed and only from within the `size` of the payload.
+    pub async fn read(
+        socket: impl TSocketAlias,
+        size: usize,
+        transfer_type: TransferType,
+        framing: Framing,
+    ) -> Result<(bool, u64, bool, Rejection, impl TSocketAlias), io::Error> {
+        let (proto, socket) = read_message::<ProtoAnswer>(socket, framing).await?;
+        if proto.offset > 0 && (transfer_type != TransferType::File || proto.offset > size as u64) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid offset {} in the answer", proto.offset),
+            ));
+        }
+        let reason = RejectReason::from_i32(proto.reason).unwrap_or(RejectReason::Rejected);
+        let rejection = Rejection::with_note(reason, Some(proto.note));
 
-        Ok((proto.accepted, socket))
+        Ok((
+            proto.accepted,
+            proto.offset,
+            proto.confirm,
+            rejection,
+            socket,
+        ))
     }
 
     pub async fn write(
-        mut socket: impl TSocketAlias,
+        socket: impl TSocketAlias,
         accepted: bool,
         hash: String,
+        offset: u64,
+        confirm: bool,
+        framing: Framing,
+    ) -> Result<((), impl TSocketAlias), io::Error> {
+        let proto = ProtoAnswer {
+            accepted,
+            hash,
+            offset,
+            reason: RejectReason::Rejected as i32,
+            note: String::new(),
+            confirm,
+        };
+        let socket = write_message(socket, &proto, framing).await?;
+        Ok(((), socket))
+    }
+
+    /// Older peers see only that the payload was not accepted
+    pub async fn reject(
+        socket: impl TSocketAlias,
+        hash: String,
+        rejection: &Rejection,
+        framing: Framing,
     ) -> Result<((), impl TSocketAlias), io::Error> {
-        let proto = ProtoAnswer { accepted, hash };
-        let len = proto.encoded_len();
-        let fill = vec![0; PACKET_SIZE - len];
-        let mut buf = Vec::with_capacity(len);
-        proto.encode(&mut buf)?;
-
-        socket.write(&buf[..len]).await?;
-        socket.write(&fill).await?;
-        socket.flush().await?;
+        let proto = ProtoAnswer {
+            accepted: false,
+            hash,
+            offset: 0,
+            reason: rejection.reason as i32,
+            note: rejection.note.clone().unwrap_or_default(),
+            confirm: false,
+        };
+        let socket = write_message(socket, &proto, framing).await?;
         Ok(((), socket))
     }
 }
 
-async fn read_from_socket(
+async fn write_message(
     mut socket: impl TSocketAlias,
-) -> Result<(Vec<u8>, impl TSocketAlias), io::Error> {
-    let mut read = 0;
-    let mut data: Vec<u8> = vec![];
-    loop {
-        let mut buff = [0u8; PACKET_SIZE];
-        match socket.read(&mut buff).await {
-            Ok(n) => {
-                read += n;
-                data.extend(&buff[..n]);
-                if read >= PACKET_SIZE {
-                    break;
-                }
+    message: &impl Message,
+    framing: Framing,
+) -> Result<impl TSocketAlias, io::Error> {
+    let len = message.encoded_len();
+    let mut buf = Vec::with_capacity(len);
+    message.encode(&mut buf)?;
+
+    match framing {
+        Framing::Padded => {
+            if len > PACKET_SIZE {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Message is too long for the legacy protocol",
+                ));
+            }
+            socket.write_all(&buf).await?;
+
+            // Append null bytes to the stream to transmit the full packet
+            let fill = vec![0; PACKET_SIZE - len];
+            socket.write_all(&fill).await?;
+        }
+        Framing::LengthPrefixed => {
+            if len > MAX_MESSAGE_SIZE {
+                return Err(Error::new(ErrorKind::InvalidInput, "Message is too long"));
             }
-            Err(e) => return Err(e),
+            socket.write_all(&(len as u32).to_be_bytes()).await?;
+            socket.write_all(&buf).await?;
         }
     }
+    socket.flush().await?;
+    Ok(socket)
+}
+
+async fn read_message<M: Message + Default>(
+    mut socket: impl TSocketAlias,
+    framing: Framing,
+) -> Result<(M, impl TSocketAlias), io::Error> {
+    let data = match framing {
+        Framing::Padded => read_from_socket(&mut socket).await?,
+        Framing::LengthPrefixed => {
+            let mut len = [0u8; 4];
+            socket.read_exact(&mut len).await?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len > MAX_MESSAGE_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "Message is too long"));
+            }
+            let mut data = vec![0u8; len];
+            socket.read_exact(&mut data).await?;
+            data
+        }
+    };
+    let message = M::decode(&data[..])?;
+    Ok((message, socket))
+}
+
+/// Reads the padded packet of the legacy protocol
+async fn read_from_socket(socket: &mut impl TSocketAlias) -> Result<Vec<u8>, io::Error> {
+    let mut data = vec![0u8; PACKET_SIZE];
+    socket.read_exact(&mut data).await?;
 
     // Remove all extra null bytes from the buffer
     data.retain(|x| *x != 0u8);
-    Ok((data, socket))
+    Ok(data)
 }
 
-pub async fn hash_contents(mut file: impl AsyncRead + Unpin) -> Result<(String, u64), Error> {
-    let mut state = Md5::default();
-    let mut buffer = [0u8; HASH_BUFFER_SIZE];
-    let mut i: u64 = 0;
-    loop {
-        match file.read(&mut buffer).await {
-            Ok(n) if n == 0 => {
-                break;
-            }
-            Ok(n) => {
-                i += n as u64;
-                state.update(&buffer[..n]);
-            }
-            Err(e) => return Err(e),
-        };
-    }
-    let hash = hex::encode::<Vec<u8>>(state.finalize().to_vec());
-    Ok((hash, i))
+pub async fn hash_contents(
+    file: impl AsyncRead + Unpin,
+    algorithm: HashAlgorithm,
+) -> Result<(String, u64), Error> {
+    let mut hasher = Hasher::new(algorithm);
+    let size = hasher.update_from(file).await?;
+    Ok((hasher.finalize(), size))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::p2p::transfer::metadata::hash_contents;
+    use crate::p2p::transfer::metadata::{
+        hash_contents, sanitize_file_name, Answer, Framing, HashAlgorithm, Hasher, Metadata,
+        RejectReason, Rejection, MAX_FILE_NAME_LENGTH,
+    };
+    use crate::p2p::TransferType;
     use async_std::fs::File;
-    use std::io::{Seek, SeekFrom, Write};
+    use futures::io::Cursor;
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_batch_needs_framed_protocol() {
+        assert!(Framing::from_protocol("/transfer/1.2").supports_batch());
+        assert!(!Framing::from_protocol("/transfer/1.1").supports_batch());
+    }
+
+    #[async_std::test]
+    async fn test_framed_answer_keeps_zero_bytes() {
+        // Offset 256 is encoded with a null byte, which the padded framing used to drop
+        let mut socket = Cursor::new(Vec::new());
+        Answer::write(
+            &mut socket,
+            false,
+            String::new(),
+            256,
+            false,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        socket.set_position(0);
+        let (accepted, offset, _, _, _) = Answer::read(
+            &mut socket,
+            1024,
+            TransferType::File,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        assert!(!accepted);
+        assert_eq!(offset, 256);
+    }
+
+    #[async_std::test]
+    async fn test_padded_answer_is_understood() {
+        let mut socket = Cursor::new(Vec::new());
+        Answer::write(
+            &mut socket,
+            true,
+            "abc".to_string(),
+            0,
+            false,
+            Framing::Padded,
+        )
+        .await
+        .unwrap();
+        assert_eq!(socket.get_ref().len(), super::PACKET_SIZE);
+
+        socket.set_position(0);
+        let (accepted, offset, confirm, _, _) =
+            Answer::read(&mut socket, 1024, TransferType::File, Framing::Padded)
+                .await
+                .unwrap();
+
+        assert!(accepted);
+        assert_eq!(offset, 0);
+        assert!(!confirm);
+    }
+
+    #[async_std::test]
+    async fn test_accepted_answer_confirms() {
+        let mut socket = Cursor::new(Vec::new());
+        Answer::write(
+            &mut socket,
+            true,
+            String::new(),
+            0,
+            true,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        socket.set_position(0);
+        let (accepted, _, confirm, _, _) = Answer::read(
+            &mut socket,
+            1024,
+            TransferType::File,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        assert!(accepted);
+        assert!(confirm);
+    }
+
+    #[async_std::test]
+    async fn test_answer_offset_out_of_range() {
+        let mut socket = Cursor::new(Vec::new());
+        Answer::write(
+            &mut socket,
+            true,
+            String::new(),
+            2048,
+            true,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        socket.set_position(0);
+        let result = Answer::read(
+            &mut socket,
+            1024,
+            TransferType::File,
+            Framing::LengthPrefixed,
+        )
+        .await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+
+        // Text is always sent whole
+        socket.set_position(0);
+        let result = Answer::read(
+            &mut socket,
+            4096,
+            TransferType::Text,
+            Framing::LengthPrefixed,
+        )
+        .await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[async_std::test]
+    async fn test_reject_reason() {
+        let mut socket = Cursor::new(Vec::new());
+        let rejection = Rejection::new(RejectReason::NoSpace);
+        Answer::reject(
+            &mut socket,
+            String::new(),
+            &rejection,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        socket.set_position(0);
+        let (accepted, _, _, rejection, _) = Answer::read(
+            &mut socket,
+            1024,
+            TransferType::File,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        assert!(!accepted);
+        assert_eq!(rejection, Rejection::new(RejectReason::NoSpace));
+    }
+
+    #[async_std::test]
+    async fn test_reject_with_note() {
+        let mut socket = Cursor::new(Vec::new());
+        let note = Some("  Send it zipped please ".to_string());
+        let rejection = Rejection::with_note(RejectReason::Declined, note);
+        Answer::reject(
+            &mut socket,
+            String::new(),
+            &rejection,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        socket.set_position(0);
+        let (_, _, _, rejection, _) = Answer::read(
+            &mut socket,
+            1024,
+            TransferType::File,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(rejection.reason, RejectReason::Declined);
+        assert_eq!(rejection.note.as_deref(), Some("Send it zipped please"));
+        assert_eq!(
+            rejection.to_string(),
+            "Payload was declined: \"Send it zipped please\""
+        );
+    }
+
+    #[async_std::test]
+    async fn test_plain_answer_has_no_note() {
+        let mut socket = Cursor::new(Vec::new());
+        Answer::write(
+            &mut socket,
+            false,
+            String::new(),
+            0,
+            false,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        socket.set_position(0);
+        let (_, _, _, rejection, _) = Answer::read(
+            &mut socket,
+            1024,
+            TransferType::File,
+            Framing::LengthPrefixed,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(rejection, Rejection::new(RejectReason::Rejected));
+    }
+
+    #[test]
+    fn test_valid_hash() {
+        let mut meta = Metadata {
+            name: "file.txt".to_string(),
+            hash: "e4d7f1b4ed2e42d15898f4b27b019da4".to_string(),
+            size: 10,
+            transfer_type: TransferType::File,
+            count: 1,
+            resumable: true,
+            hash_algorithm: HashAlgorithm::Md5,
+            manifest: false,
+            files: 1,
+            confirm: true,
+        };
+        assert!(meta.has_valid_hash());
+
+        meta.hash_algorithm = HashAlgorithm::Sha256;
+        assert!(!meta.has_valid_hash());
+
+        meta.hash_algorithm = HashAlgorithm::Md5;
+        meta.hash = "../../../../etc/passwd00000000000".to_string();
+        assert!(!meta.has_valid_hash());
+
+        meta.transfer_type = TransferType::Dir;
+        meta.hash = "directory".to_string();
+        assert!(meta.has_valid_hash());
+    }
 
     #[async_std::test]
     #[cfg(not(target_os = "windows"))]
@@ -184,7 +754,7 @@ mod tests {
         write!(file, "I'll fly to device!").unwrap();
         file.seek(SeekFrom::Start(0)).unwrap();
         let async_file = File::from(file);
-        let (hash, size) = hash_contents(async_file).await.unwrap();
+        let (hash, size) = hash_contents(async_file, HashAlgorithm::Md5).await.unwrap();
 
         assert_eq!(hash, "a909b834a8f95194ee2ce975e38cec31".to_string());
         assert_eq!(size, 19);
@@ -198,9 +768,76 @@ mod tests {
         write!(file, "I'll fly to device!").unwrap();
         file.seek(SeekFrom::Start(0)).unwrap();
         let async_file = File::from(file);
-        let (hash, size) = hash_contents(async_file).await.unwrap();
+        let (hash, size) = hash_contents(async_file, HashAlgorithm::Md5).await.unwrap();
 
         assert_eq!(hash, "a909b834a8f95194ee2ce975e38cec31".to_string());
         assert_eq!(size, 19);
     }
+
+    #[async_std::test]
+    async fn test_hash_sha256() {
+        let data = "I'll fly to device!".as_bytes();
+        let (hash, size) = hash_contents(data, HashAlgorithm::Sha256).await.unwrap();
+
+        assert_eq!(
+            hash,
+            "8b8de9ed7122ddb61b6481c46c009e757778b41d2986ea43d97415404c83dacd".to_string()
+        );
+        assert_eq!(size, 19);
+    }
+
+    #[test]
+    fn test_hash_in_chunks() {
+        // Data arriving in pieces gives the same digest as the whole file
+        let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+        hasher.update(b"I'll fly ");
+        hasher.update(b"to device!");
+
+        assert_eq!(
+            hasher.finalize(),
+            "8b8de9ed7122ddb61b6481c46c009e757778b41d2986ea43d97415404c83dacd".to_string()
+        );
+    }
+
+    #[test]
+    fn test_sanitize_plain_name() {
+        assert_eq!(sanitize_file_name("a-file.txt"), "a-file.txt");
+        assert_eq!(sanitize_file_name(".hidden"), ".hidden");
+    }
+
+    #[test]
+    fn test_sanitize_traversal() {
+        assert_eq!(sanitize_file_name("../../.bashrc"), ".bashrc");
+        assert_eq!(sanitize_file_name("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_file_name(r"..\..\Windows\win.ini"), "win.ini");
+        assert_eq!(sanitize_file_name("dir/.."), "dir");
+        assert_eq!(sanitize_file_name(".."), "dragit-file");
+        assert_eq!(sanitize_file_name("..."), "dragit-file");
+        assert_eq!(sanitize_file_name(""), "dragit-file");
+    }
+
+    #[test]
+    fn test_sanitize_control_characters() {
+        assert_eq!(sanitize_file_name("new\nline\t.txt"), "newline.txt");
+        assert_eq!(sanitize_file_name("\0"), "dragit-file");
+    }
+
+    #[test]
+    fn test_sanitize_reserved_names() {
+        assert_eq!(sanitize_file_name("CON"), "_CON");
+        assert_eq!(sanitize_file_name("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_file_name("com1.tar.gz"), "_com1.tar.gz");
+        assert_eq!(sanitize_file_name("console.txt"), "console.txt");
+        assert_eq!(sanitize_file_name("file. . "), "file");
+    }
+
+    #[test]
+    fn test_sanitize_long_name() {
+        let name = format!("{}.txt", "ż".repeat(300));
+        let result = sanitize_file_name(&name);
+
+        assert!(result.len() <= MAX_FILE_NAME_LENGTH);
+        assert!(result.starts_with("żż"));
+        assert!(result.ends_with(".txt"));
+    }
 }
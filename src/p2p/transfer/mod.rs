@@ -1,6 +1,7 @@
 pub mod behaviour;
 pub mod directory;
 pub mod file;
+pub mod handler;
 pub mod metadata;
 pub mod protocol;
 
@@ -1,36 +1,54 @@
-use async_std::fs::OpenOptions;
+use async_std::fs::{self, OpenOptions};
 use std::fmt;
 use std::fs::remove_file;
-use std::io::ErrorKind;
-use std::sync::Arc;
-
+use std::io::{ErrorKind, SeekFrom};
+use std::path::Path;
 use std::task::{Context, Poll};
-use std::time::Instant;
-use std::{io, iter, pin::Pin};
+use std::time::{Duration, Instant};
+use std::{io, pin::Pin};
 
-use async_std::channel::{Receiver, Sender};
+use async_std::channel::Sender;
 use async_std::io::BufReader;
-use async_std::sync::Mutex;
 use async_std::task;
 
-use futures::future;
+use futures::channel::oneshot;
+use futures::future::{self, Either};
 use futures::io as futio;
 use futures::prelude::*;
-use libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
-
-use crate::p2p::commands::TransferCommand;
-use crate::p2p::peer::{Direction, PeerEvent};
-use crate::p2p::transfer::directory::unzip_stream;
-use crate::p2p::transfer::file::{get_hash_from_payload, FileToSend, Payload, StreamOption};
-use crate::p2p::transfer::metadata::{Answer, Metadata};
-use crate::p2p::util::{self, TSocketAlias, CHUNK_SIZE};
+use libp2p::core::{InboundUpgrade, OutboundUpgrade, PeerId, UpgradeInfo};
+
+use crate::p2p::commands::{
+    ActiveTransfers, PendingAnswers, ReceivingClaim, ReceivingFiles, TransferCommand, TransferId,
+};
+use crate::p2p::peer::{Direction, PeerEvent, PeerTable};
+use crate::p2p::transfer::directory::{move_extracted, unzip_stream, ArchiveProgress};
+use crate::p2p::transfer::file::{FileToSend, HashCache, HashCacheKey, Payload, StreamOption};
+use crate::p2p::transfer::metadata::{
+    Answer, Framing, HashAlgorithm, Hasher, Metadata, RejectReason, Rejection, PROTOCOL_VERSIONS,
+};
+use crate::p2p::util::{self, IdleTimeout, ProgressTracker, TSocketAlias, CHUNK_SIZE};
 use crate::p2p::TransferType;
-use crate::user_data;
+use crate::user_data::{self, CollisionPolicy};
+
+/// Transfer fails if no data moves for this long. There is no limit for the whole transfer.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Incoming payloads waiting for the user's decision at the same time, the rest is rejected
+const MAX_PENDING_ANSWERS: usize = 8;
+
+/// Sent by the receiver to stop the transfer. The sender stops it by closing the stream.
+const CANCEL_SIGNAL: u8 = 0x18;
+/// Sent by the receiver once the payload matched its digest, if the sender asked for it
+const VERIFIED_SIGNAL: u8 = 0x06;
+/// Sent by the receiver instead of `VERIFIED_SIGNAL` if the payload arrived corrupted
+const CORRUPTED_SIGNAL: u8 = 0x15;
 
 #[derive(Clone, Debug)]
 pub enum ProtocolEvent {
     Received(TransferPayload),
     Sent,
+    /// Remote peer doesn't support any of our transfer protocol versions
+    Incompatible,
 }
 
 // Outgoing transfer to remote peer
@@ -38,6 +56,9 @@ pub enum ProtocolEvent {
 pub struct TransferOut {
     pub file: FileToSend,
     pub sender_queue: Sender<PeerEvent>,
+    pub transfers: ActiveTransfers,
+    pub hashes: HashCache,
+    pub peers: PeerTable,
 }
 
 // Incoming transfer to current host
@@ -46,22 +67,126 @@ pub struct TransferPayload {
     pub name: String,
     pub payload: Payload,
     pub hash: String,
+    pub verification: Verification,
     pub size_bytes: usize,
     pub sender_queue: Sender<PeerEvent>,
-    pub receiver: Arc<Mutex<Receiver<TransferCommand>>>,
+    pub answers: PendingAnswers,
+    pub transfers: ActiveTransfers,
+    pub receiving: ReceivingFiles,
     pub target_path: Option<String>,
+    /// Read from the config if not set, resolved once the transfer is accepted
+    pub collision_policy: Option<CollisionPolicy>,
+    /// Read from the config if not set
+    pub answer_timeout: Option<Duration>,
+    pub peer: Option<PeerId>,
+    pub peers: PeerTable,
 }
 
-impl TransferPayload {
-    pub fn check_file(&self) -> Result<(), io::Error> {
-        let (hash_from_disk, _) =
-            task::block_on(async { get_hash_from_payload(&self.payload).await })?;
+/// Result of the checks done while the payload was written to the disk
+#[derive(Clone, Debug)]
+pub enum Verification {
+    /// Digest of the received file, to be compared with the one from the metadata
+    Checksum(String),
+    /// Files of the directory which didn't match the manifest
+    Manifest(Vec<String>),
+    /// Sender didn't provide anything to verify the payload with
+    Unverified,
+}
 
-        if hash_from_disk != self.hash {
-            Err(io::Error::new(ErrorKind::InvalidData, "File corrupted!"))
-        } else {
-            Ok(())
+/// Marks the error of the transfer stopped on purpose, so it's not reported as a failure
+#[derive(Debug)]
+struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transfer cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Resolves once the time is up, never if there is no limit
+async fn deadline(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => task::sleep(timeout).await,
+        None => future::pending().await,
+    }
+}
+
+fn cancelled_error() -> io::Error {
+    io::Error::new(ErrorKind::Interrupted, Cancelled)
+}
+
+fn is_cancelled(err: &io::Error) -> bool {
+    err.get_ref().map_or(false, |e| e.is::<Cancelled>())
+}
+
+/// Remembers whether the stream was closed by the other side,
+/// which tells the cancelled transfer from the broken one.
+struct EofReader<R> {
+    inner: R,
+    eof: bool,
+}
+
+impl<R> EofReader<R> {
+    fn new(inner: R) -> Self {
+        EofReader { inner, eof: false }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for EofReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(0)) = poll {
+            if !buf.is_empty() {
+                self.eof = true;
+            }
         }
+        poll
+    }
+}
+
+/// Reads the next signal of the receiver, None if it closed the stream
+async fn read_signal(mut reader: impl AsyncRead + Unpin) -> Result<Option<u8>, io::Error> {
+    let mut buff = [0u8; 1];
+    match reader.read(&mut buff).await? {
+        0 => Ok(None),
+        _ => Ok(Some(buff[0])),
+    }
+}
+
+async fn remove_partial_data(paths: &[String]) {
+    for path in paths {
+        let result = match fs::metadata(path).await {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(path).await,
+            Ok(_) => fs::remove_file(path).await,
+            Err(_) => continue,
+        };
+        if let Err(e) = result {
+            warn!("Could not remove {}: {:?}", path, e);
+        }
+    }
+}
+
+impl Verification {
+    /// Returns names of the corrupted files
+    fn check(&self, name: &str, hash: &str) -> Result<(), Vec<String>> {
+        match self {
+            Verification::Checksum(checksum) if checksum != hash => Err(vec![name.to_string()]),
+            Verification::Manifest(corrupted) if !corrupted.is_empty() => Err(corrupted.to_owned()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TransferPayload {
+    /// Returns names of the corrupted files
+    pub fn check_file(&self) -> Result<(), Vec<String>> {
+        self.verification.check(&self.name, &self.hash)
     }
 
     pub fn cleanup(&self) -> Result<(), io::Error> {
@@ -77,273 +202,817 @@ impl TransferPayload {
         Ok(())
     }
 
-    async fn notify_incoming_file_event(&self, meta: &Metadata) {
+    async fn notify_incoming_file_event(
+        &self,
+        id: TransferId,
+        meta: &Metadata,
+        peer: PeerId,
+        available: Option<u64>,
+    ) {
         let name = meta.name.to_string();
         let hash = meta.hash.to_string();
         let size = meta.size;
         let transfer_type = meta.transfer_type;
-        let event = PeerEvent::FileIncoming(name, hash, size, transfer_type);
+        let count = meta.count;
+        let sender = self.peers.resolve(&peer);
+        info!(
+            "Incoming {} {} from {} ({:?})",
+            id,
+            name,
+            sender.display_name(),
+            sender.os
+        );
+        let event = PeerEvent::FileIncoming(
+            id,
+            name,
+            hash,
+            size,
+            transfer_type,
+            count,
+            sender,
+            available,
+        );
         util::notify(&self.sender_queue, event).await;
     }
 
-    async fn block_for_answer(
+    /// Answers with the denial if nobody decided before the timeout
+    async fn wait_for_answer(
         &self,
-        receiver: Arc<Mutex<Receiver<TransferCommand>>>,
-    ) -> TransferCommand {
-        let mut r = receiver.lock().await;
+        id: TransferId,
+        answer: oneshot::Receiver<TransferCommand>,
+        mut socket: impl TSocketAlias,
+    ) -> Result<TransferCommand, io::Error> {
+        let timeout = match user_data::get_answer_timeout(self.answer_timeout) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                self.answers.remove(&id);
+                return Err(e);
+            }
+        };
+        let timed_out = Box::pin(deadline(timeout));
+
+        // The sender doesn't write anything until it gets the answer,
+        // so reading from the socket returns only if the sender gave up.
+        let mut buff = [0u8; 1];
+        let closed = socket.read(&mut buff);
+
         // Wait for the user to confirm the incoming file
-        task::block_on(future::poll_fn(
-            move |context: &mut Context| match Receiver::poll_next_unpin(&mut r, context) {
-                Poll::Ready(Some(choice)) => {
-                    info!("Got the choice: {:?}", choice);
-                    Poll::Ready(choice)
-                }
-                Poll::Ready(None) => {
-                    info!("Nothing to handle now");
-                    Poll::Pending
+        match future::select(future::select(answer, timed_out), closed).await {
+            Either::Left((Either::Left((Ok(choice), _)), _)) => {
+                info!("Got the choice: {:?}", choice);
+                Ok(choice)
+            }
+            Either::Left((Either::Left((Err(_), _)), _)) => {
+                self.answers.remove(&id);
+                Err(io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "Nobody answered the transfer",
+                ))
+            }
+            Either::Left((Either::Right(_), _)) => {
+                info!("Transfer {} was not answered in time", id);
+                self.answers.remove(&id);
+                util::notify(&self.sender_queue, PeerEvent::AnswerTimedOut(id)).await;
+                let rejection = Rejection::new(RejectReason::TimedOut);
+                Ok(TransferCommand::Deny(id, rejection))
+            }
+            Either::Right((Err(e), _)) => {
+                self.answers.remove(&id);
+                Err(e)
+            }
+            Either::Right((Ok(_), _)) => {
+                info!("Transfer {} cancelled by peer before the answer", id);
+                self.answers.remove(&id);
+                util::notify_cancelled_by_peer(&self.sender_queue, id).await;
+                Err(cancelled_error())
+            }
+        }
+    }
+
+    /// Decides what happens if the name of the payload is already taken, the user is asked
+    /// if the policy says so. Returns None if the payload should be skipped.
+    async fn resolve_collision_policy(
+        &self,
+        id: TransferId,
+        meta: &Metadata,
+        command: TransferCommand,
+        socket: impl TSocketAlias,
+    ) -> Result<Option<CollisionPolicy>, io::Error> {
+        let policy = match command {
+            TransferCommand::Resolve(_, policy) => policy,
+            _ => user_data::get_collision_policy(self.collision_policy)?,
+        };
+        let path =
+            user_data::get_target_path(&meta.get_safe_file_name(), self.target_path.as_ref())?;
+        // Items of the batch are checked one by one once unpacked, it's too late to ask then.
+        // Text never lands in the target directory.
+        let exists = fs::symlink_metadata(&path).await.is_ok();
+        if !exists || matches!(meta.transfer_type, TransferType::Batch | TransferType::Text) {
+            return Ok(Some(policy));
+        }
+
+        let policy = match policy {
+            CollisionPolicy::Ask => {
+                let answer = self.answers.register(id);
+                let event = PeerEvent::FileExists(id, meta.name.to_string());
+                util::notify(&self.sender_queue, event).await;
+                match self.wait_for_answer(id, answer, socket).await? {
+                    // Nothing is lost if the user didn't choose
+                    TransferCommand::Resolve(_, CollisionPolicy::Ask) => CollisionPolicy::Rename,
+                    TransferCommand::Resolve(_, policy) => policy,
+                    TransferCommand::Accept(_) => CollisionPolicy::Rename,
+                    TransferCommand::Deny(..) | TransferCommand::Cancel(_) => CollisionPolicy::Skip,
                 }
-                Poll::Pending => Poll::Pending,
-            },
-        ))
+            }
+            policy => policy,
+        };
+        match policy {
+            CollisionPolicy::Skip => Ok(None),
+            policy => Ok(Some(policy)),
+        }
+    }
+
+    /// Bytes of the file already received in the previous attempt
+    async fn get_resume_offset(&self, meta: &Metadata) -> usize {
+        if meta.transfer_type != TransferType::File || !meta.resumable {
+            return 0;
+        }
+        let partial_path = match user_data::get_partial_path(&meta.hash, self.target_path.as_ref())
+        {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("Can't resume the transfer: {:?}", e);
+                return 0;
+            }
+        };
+        match fs::metadata(&partial_path).await {
+            Ok(partial) if (partial.len() as usize) < meta.size => {
+                info!("Resuming {} from {} bytes", meta.name, partial.len());
+                partial.len() as usize
+            }
+            _ => 0,
+        }
     }
 
+    /// Writes the stream to the file and returns the number of bytes
+    /// together with the digest of the whole file
     async fn stream_file(
         &mut self,
         path: &str,
         mut reader: impl AsyncRead + Unpin,
-        size: usize,
+        id: TransferId,
+        offset: usize,
+        meta: &Metadata,
         direction: &Direction,
-    ) -> Result<usize, io::Error> {
-        info!("Path: {}", path);
+    ) -> Result<(usize, String), io::Error> {
+        info!("Path: {}, offset: {}", path, offset);
+        let size = meta.size;
         let mut file = OpenOptions::new()
+            .read(true)
             .write(true)
             .create(true)
             .open(path)
-            .await
-            .expect("Opening failed!");
-        let mut counter: usize = 0;
-        let mut current_size: usize = 0;
+            .await?;
+        // Drop anything after the offset, the sender continues exactly there
+        file.set_len(offset as u64).await?;
+
+        // Data from the previous attempt is a part of the digest as well
+        let mut hasher = Hasher::new(meta.hash_algorithm);
+        if offset > 0 {
+            hasher.update_from((&mut file).take(offset as u64)).await?;
+        }
+        file.seek(SeekFrom::Start(offset as u64)).await?;
+        let mut tracker = ProgressTracker::new(offset, size);
+        util::notify_progress(&self.sender_queue, id, tracker.report(), direction).await;
         loop {
             let mut buff = vec![0u8; CHUNK_SIZE];
             match reader.read(&mut buff).await {
                 Ok(n) => {
                     if n > 0 {
-                        counter += n;
-                        current_size += n;
-
-                        file.write(&buff[..n]).await?;
+                        file.write_all(&buff[..n]).await?;
+                        hasher.update(&buff[..n]);
 
-                        if util::time_to_notify(current_size, size) {
-                            util::notify_progress(&self.sender_queue, counter, size, &direction)
+                        if let Some(progress) = tracker.advance(n) {
+                            util::notify_progress(&self.sender_queue, id, progress, direction)
                                 .await;
-                            current_size = 0;
                         }
                     } else {
                         file.close().await?;
-                        util::notify_progress(&self.sender_queue, counter, size, &direction).await;
+                        util::notify_progress(&self.sender_queue, id, tracker.report(), direction)
+                            .await;
                         break;
                     }
                 }
                 Err(e) => return Err(e),
             }
         }
-        Ok(counter)
+        Ok((tracker.counter(), hasher.finalize()))
     }
 
     async fn stream_dir(
         &self,
         path: String,
-        reader: BufReader<impl TSocketAlias + 'static>,
-        size: usize,
-        direction: &Direction,
-    ) -> Result<usize, io::Error> {
-        let sender_copy = self.sender_queue.clone();
-        let task = unzip_stream(path, reader, sender_copy, size, direction.clone()).await?;
-        let received_bytes = task.await?;
-        Ok(received_bytes)
+        reader: impl AsyncRead + Unpin,
+        meta: &Metadata,
+        id: TransferId,
+    ) -> Result<(usize, Vec<String>, Vec<String>), io::Error> {
+        unzip_stream(path, reader, meta, &self.sender_queue, id).await
     }
 
+    /// Streams the payload to the disk. Paths created on the way are added to `created`,
+    /// so they can be removed in case the transfer is cancelled.
     async fn read_file_payload(
         &mut self,
-        socket: impl TSocketAlias + 'static,
+        socket: impl TSocketAlias,
         meta: &Metadata,
-        size: usize,
+        id: TransferId,
+        offset: usize,
+        created: &mut Vec<String>,
         direction: &Direction,
-    ) -> Result<(usize, String), io::Error> {
-        let reader = BufReader::new(socket);
+    ) -> Result<(usize, String, Payload, Verification), io::Error> {
+        let size = meta.size;
+        let mut reader = EofReader::new(BufReader::new(socket));
+        let collisions = self.collision_policy.unwrap_or(CollisionPolicy::Rename);
 
-        let path =
+        let mut path =
             user_data::get_target_path(&meta.get_safe_file_name(), self.target_path.as_ref())?;
 
-        let counter = match meta.transfer_type {
-            TransferType::File => self.stream_file(&path, reader, size, direction).await?,
-            TransferType::Text => self.stream_file(&path, reader, size, direction).await?,
-            TransferType::Dir => {
-                self.stream_dir(path.clone(), reader, size, direction)
-                    .await?
+        let (counter, payload, verification) = match meta.transfer_type {
+            TransferType::File => {
+                // Data goes to the partial file first, so the interrupted transfer can be resumed
+                let partial_path =
+                    user_data::get_partial_path(&meta.hash, self.target_path.as_ref())?;
+                created.push(partial_path.clone());
+                let (counter, checksum) = self
+                    .stream_file(&partial_path, &mut reader, id, offset, meta, direction)
+                    .await?;
+                if counter < size {
+                    // Stream was closed cleanly before the end, broken connection ends with an error
+                    return Err(cancelled_error());
+                }
+                let verification = Verification::Checksum(checksum);
+                if verification.check(&meta.name, &meta.hash).is_ok() {
+                    // Checked again, the file could have shown up during the transfer
+                    path = match user_data::resolve_collision(&path, collisions) {
+                        Some(path) => path,
+                        None => {
+                            remove_partial_data(&[partial_path]).await;
+                            return Err(io::Error::new(ErrorKind::AlreadyExists, "File exists"));
+                        }
+                    };
+                    fs::rename(&partial_path, &path).await?;
+                } else {
+                    // Corrupted data can't be used to resume the transfer either
+                    remove_partial_data(&[partial_path]).await;
+                }
+                (counter, Payload::File(path.clone()), verification)
+            }
+            TransferType::Text => {
+                // Text is read into the memory, the file is removed right after
+                path = user_data::get_partial_path(&meta.hash, self.target_path.as_ref())?;
+                created.push(path.clone());
+                let (counter, checksum) = self
+                    .stream_file(&path, &mut reader, id, 0, meta, direction)
+                    .await?;
+                if counter < size {
+                    return Err(cancelled_error());
+                }
+                let payload = Payload::new(meta.transfer_type, path.clone())?;
+                (counter, payload, Verification::Checksum(checksum))
+            }
+            TransferType::Dir | TransferType::Batch => {
+                // Unpacked into the staging directory, its contents are moved once they're complete
+                let staging = user_data::create_staging_dir(self.target_path.as_ref())?;
+                created.push(staging.clone());
+                let result = self
+                    .stream_dir(staging.clone(), &mut reader, meta, id)
+                    .await;
+                let (counter, roots, corrupted) = match result {
+                    Ok(result) => result,
+                    Err(_) if reader.eof => return Err(cancelled_error()),
+                    Err(e) => return Err(e),
+                };
+                let verification = if meta.manifest {
+                    Verification::Manifest(corrupted)
+                } else {
+                    Verification::Unverified
+                };
+                let roots = if verification.check(&meta.name, &meta.hash).is_ok() {
+                    let target_dir = Path::new(&path).parent().unwrap_or(Path::new(&path));
+                    move_extracted(&roots, target_dir, collisions)?
+                } else {
+                    vec![]
+                };
+                remove_partial_data(&[staging]).await;
+
+                match meta.transfer_type {
+                    // Batch items land next to each other in the downloads directory
+                    TransferType::Batch => (counter, Payload::Batch(roots), verification),
+                    // Directory could have been renamed because of the collision
+                    _ => {
+                        let dir = roots.first().cloned().unwrap_or_else(|| path.clone());
+                        (counter, Payload::Dir(dir), verification)
+                    }
+                }
             }
         };
 
-        Ok((counter, path))
+        Ok((counter, path, payload, verification))
     }
 
-    async fn read_socket(&mut self, socket: impl TSocketAlias + 'static) -> Result<(), io::Error> {
+    /// Returns the free space in the target directory, if it's known,
+    /// or the reason to reject the payload without asking the user
+    async fn check_incoming(
+        &self,
+        meta: &Metadata,
+    ) -> Result<(Option<u64>, Option<ReceivingClaim>), Rejection> {
+        if !meta.has_valid_hash() {
+            return Err(Rejection::new(RejectReason::HashMismatch));
+        }
+        if self.answers.len() >= MAX_PENDING_ANSWERS {
+            return Err(Rejection::new(RejectReason::Busy));
+        }
+        // Partial data is named after the digest, so the same file can't be received twice at once
+        let claim = match meta.transfer_type {
+            TransferType::File | TransferType::Text => match self.receiving.claim(&meta.hash) {
+                Some(claim) => Some(claim),
+                None => {
+                    info!("{} is already being received", meta.name);
+                    return Err(Rejection::new(RejectReason::Busy));
+                }
+            },
+            TransferType::Dir | TransferType::Batch => None,
+        };
+        // Partial data from the previous attempt is already on the disk
+        let needed = meta.size.saturating_sub(self.get_resume_offset(meta).await) as u64;
+        let available = user_data::get_available_space(self.target_path.as_ref());
+        match available {
+            Some(available) if available < needed => {
+                info!("{} bytes needed, but only {} available", needed, available);
+                Err(Rejection::new(RejectReason::NoSpace))
+            }
+            available => Ok((available, claim)),
+        }
+    }
+
+    async fn read_socket(
+        &mut self,
+        mut socket: impl TSocketAlias,
+        framing: Framing,
+    ) -> Result<(), io::Error> {
         let direction = Direction::Incoming;
-        let (meta, mut socket) = Metadata::read(socket).await?;
-        info!("Meta received! \n{}", meta);
+        let peer = self
+            .peer
+            .clone()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "Sender is not known"))?;
+        let (meta, _) = Metadata::read(&mut socket, framing).await?;
+        info!("Meta received from {}! \n{}", peer, meta);
 
-        self.notify_incoming_file_event(&meta).await;
-        let rec_cp = Arc::clone(&self.receiver);
+        // The claim is held until the transfer is over, whatever the result
+        let (available, _claim) = match self.check_incoming(&meta).await {
+            Ok(checked) => checked,
+            Err(rejection) => {
+                warn!("Rejecting {}: {}", meta.name, rejection);
+                Answer::reject(&mut socket, meta.hash.to_string(), &rejection, framing).await?;
+                if rejection.reason == RejectReason::NoSpace {
+                    let message = format!("Not enough free space for {}", meta.name);
+                    util::notify_error(&self.sender_queue, &message).await;
+                }
+                return Err(io::Error::new(ErrorKind::PermissionDenied, "Rejected"));
+            }
+        };
 
-        match self.block_for_answer(rec_cp).await {
-            TransferCommand::Accept(hash) if hash == meta.hash => {
-                Answer::write(&mut socket, true, hash).await?;
+        // Each transfer gets its own id, so the answer can't reach any other transfer
+        let id = TransferId::generate();
+        let answer = self.answers.register(id);
+        self.notify_incoming_file_event(id, &meta, peer, available)
+            .await;
 
-                util::notify_progress(&self.sender_queue, 0, meta.size, &direction).await;
+        match self.wait_for_answer(id, answer, &mut socket).await? {
+            command @ (TransferCommand::Accept(_) | TransferCommand::Resolve(..)) => {
+                let policy = self
+                    .resolve_collision_policy(id, &meta, command, &mut socket)
+                    .await?;
+                self.collision_policy = match policy {
+                    Some(policy) => Some(policy),
+                    None => {
+                        info!("Skipping transfer {}, {} already exists", id, meta.name);
+                        Answer::write(&mut socket, false, meta.hash.to_string(), 0, false, framing)
+                            .await?;
+                        let event = PeerEvent::FileSkipped(meta.name.to_string());
+                        util::notify(&self.sender_queue, event).await;
+                        return Err(io::Error::new(ErrorKind::AlreadyExists, "Skipped"));
+                    }
+                };
+                let offset = self.get_resume_offset(&meta).await;
+                Answer::write(
+                    &mut socket,
+                    true,
+                    meta.hash.to_string(),
+                    offset as u64,
+                    meta.confirm,
+                    framing,
+                )
+                .await?;
+
+                let sender = self.peers.resolve(&peer);
+                let started = direction.clone();
+                util::notify_started(&self.sender_queue, id, &meta.name, sender, started).await;
 
-                let (counter, path) = match self
-                    .read_file_payload(socket, &meta, meta.size, &direction)
-                    .await
-                {
-                    Ok((counter, path)) => (counter, path),
-                    Err(err) => {
+                let cancelled = self.transfers.register(id);
+                let mut created: Vec<String> = vec![];
+                let result = {
+                    let reading = self.read_file_payload(
+                        IdleTimeout::new(&mut socket, IDLE_TIMEOUT),
+                        &meta,
+                        id,
+                        offset,
+                        &mut created,
+                        &direction,
+                    );
+                    match future::select(Box::pin(reading), cancelled).await {
+                        Either::Left((result, _)) => Some(result),
+                        Either::Right(_) => None,
+                    }
+                };
+                self.transfers.remove(&id);
+
+                let (counter, path, payload, verification) = match result {
+                    Some(Ok(result)) => result,
+                    Some(Err(err)) if is_cancelled(&err) => {
+                        info!("Transfer {} cancelled by peer", id);
+                        remove_partial_data(&created).await;
+                        util::notify_cancelled_by_peer(&self.sender_queue, id).await;
+                        return Err(err);
+                    }
+                    Some(Err(err)) => {
                         error!("Reading payload failed: {:?}", err);
-                        util::notify_error(&self.sender_queue, "Reading payload failed").await;
+                        // Partial file stays, so the transfer can be resumed
+                        if meta.transfer_type != TransferType::File {
+                            remove_partial_data(&created).await;
+                        }
+                        let message = format!("Reading payload failed: {}", err);
+                        util::notify_failed(&self.sender_queue, id, &message).await;
                         return Err(err);
                     }
+                    None => {
+                        info!("Transfer {} cancelled", id);
+                        // Sender doesn't expect anything else on the stream, except for this signal
+                        if let Err(e) = socket.write_all(&[CANCEL_SIGNAL]).await {
+                            warn!("Could not notify the sender: {:?}", e);
+                        }
+                        let _ = socket.close().await;
+                        remove_partial_data(&created).await;
+                        util::notify_cancelled(&self.sender_queue, id).await;
+                        return Err(cancelled_error());
+                    }
                 };
 
+                // Sender waits for the result before it reports the transfer as completed
+                if meta.confirm {
+                    let signal = match verification.check(&meta.name, &meta.hash) {
+                        Ok(()) => VERIFIED_SIGNAL,
+                        Err(_) => CORRUPTED_SIGNAL,
+                    };
+                    let confirmed = match socket.write_all(&[signal]).await {
+                        Ok(()) => socket.close().await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = confirmed {
+                        warn!("Could not confirm transfer {}: {:?}", id, e);
+                    }
+                }
+
                 self.name = meta.name;
                 self.hash = meta.hash;
-                self.payload = Payload::new(meta.transfer_type, path.clone())?;
+                self.verification = verification;
+                self.payload = payload;
                 self.size_bytes = counter;
 
                 // TransferPayload needs to know where is the actual file after successful transfer
                 self.target_path = Some(path);
 
+                util::notify_completed(&self.sender_queue, id).await;
                 Ok(())
             }
-            TransferCommand::Accept(hash) => {
-                warn!("Accepted hash does not match: {} {}", hash, meta.hash);
-                Answer::write(&mut socket, false, hash).await?;
-                Err(io::Error::new(
-                    ErrorKind::PermissionDenied,
-                    "Hash does not match",
-                ))
-            }
-            TransferCommand::Deny(hash) => {
-                warn!("Denied hash: {}", hash);
-                Answer::write(&mut socket, false, hash).await?;
+            command @ (TransferCommand::Deny(..) | TransferCommand::Cancel(_)) => {
+                let rejection = match command {
+                    TransferCommand::Deny(_, rejection) => rejection,
+                    // Cancelling the transfer before it started is the same as declining it
+                    _ => Rejection::new(RejectReason::Declined),
+                };
+                warn!("Denied transfer {}: {}", id, rejection);
+                Answer::reject(&mut socket, meta.hash.to_string(), &rejection, framing).await?;
                 Err(io::Error::new(ErrorKind::PermissionDenied, "Rejected"))
             }
         }
     }
 }
 
+impl TransferPayload {
+    pub async fn receive(
+        mut self,
+        socket: impl TSocketAlias,
+        framing: Framing,
+    ) -> Result<ProtocolEvent, io::Error> {
+        let start = Instant::now();
+        self.read_socket(socket, framing).await?;
+
+        info!("Finished {:?} ms", start.elapsed().as_millis());
+        Ok(ProtocolEvent::Received(self))
+    }
+}
+
 impl UpgradeInfo for TransferPayload {
     type Info = &'static str;
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = Vec<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once("/transfer/1.1")
+        PROTOCOL_VERSIONS.to_vec()
     }
 }
 
 impl TransferOut {
-    async fn write_socket(&self, socket: impl TSocketAlias) -> Result<(), io::Error> {
+    pub async fn send(
+        self,
+        socket: impl TSocketAlias,
+        framing: Framing,
+    ) -> Result<ProtocolEvent, io::Error> {
+        let start = Instant::now();
+        self.write_socket(socket, framing).await?;
+
+        info!("Finished {:?} ms", start.elapsed().as_millis());
+        Ok(ProtocolEvent::Sent)
+    }
+
+    async fn write_socket(
+        &self,
+        mut socket: impl TSocketAlias,
+        framing: Framing,
+    ) -> Result<(), io::Error> {
+        let id = TransferId::generate();
+        info!("File to send {}: {}", id, self.file);
+        let peer = self.peers.resolve(&self.file.peer);
         let direction = Direction::Outgoing;
-        info!("File to send: {}", self.file);
+        util::notify_started(&self.sender_queue, id, &self.file.name, peer, direction).await;
+
+        let cancelled = self.transfers.register(id);
+        let result = {
+            let sending = self.send_payload(&mut socket, id, framing);
+            match future::select(Box::pin(sending), cancelled).await {
+                Either::Left((result, _)) => Some(result),
+                Either::Right(_) => None,
+            }
+        };
+        self.transfers.remove(&id);
+
+        match result {
+            Some(Err(err)) => {
+                error!("Sending payload failed: {:?}", err);
+                let message = format!("Sending payload failed: {}", err);
+                util::notify_failed(&self.sender_queue, id, &message).await;
+                Err(err)
+            }
+            Some(Ok(())) => Ok(()),
+            None => {
+                info!("Transfer {} cancelled", id);
+                // Receiver recognizes the cancellation by the stream closed too early
+                if let Err(e) = socket.close().await {
+                    warn!("Could not close the stream: {:?}", e);
+                }
+                util::notify_cancelled(&self.sender_queue, id).await;
+                Ok(())
+            }
+        }
+    }
 
-        util::notify_waiting(&self.sender_queue).await;
+    async fn send_payload(
+        &self,
+        socket: impl TSocketAlias,
+        id: TransferId,
+        framing: Framing,
+    ) -> Result<(), io::Error> {
+        if self.file.transfer_type == TransferType::Batch && !framing.supports_batch() {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the other device runs an older version, send the items one by one",
+            ));
+        }
+        let (hash, size, files) = self.prepare_payload(id, framing.hash_algorithm()).await?;
+
+        util::notify_waiting(&self.sender_queue, id).await;
 
-        let (size, socket) = Metadata::write(&self.file, socket).await?;
+        let socket = Metadata::write(&self.file, hash, size, files, socket, framing).await?;
 
         // Check if remote is willing to accept our file
-        let (accepted, socket) = Answer::read(socket).await?;
-        info!("File accepted? {:?}", accepted);
+        let transfer_type = self.file.transfer_type;
+        let (accepted, offset, confirm, rejection, socket) =
+            Answer::read(socket, size, transfer_type, framing).await?;
+        info!("File accepted? {:?}, offset: {}", accepted, offset);
 
         if accepted {
-            match self.file.get_file_stream().await? {
-                StreamOption::File(file) => {
-                    self.stream_data(socket, file, size, direction).await?;
-                    Ok(())
+            // Receiver can ask to stop at any moment, so listen to it while streaming
+            let (mut reader, writer) = IdleTimeout::new(socket, IDLE_TIMEOUT).split();
+            let manifest = framing.manifest_algorithm();
+            let streaming = self.stream_payload(writer, id, offset, size, files, manifest);
+            let signal = read_signal(&mut reader);
+            let signal = match future::select(Box::pin(streaming), Box::pin(signal)).await {
+                Either::Left((result, signal)) => {
+                    result?;
+                    signal.await
                 }
-                StreamOption::Zip(file, task_handle) => {
-                    self.stream_data(socket, file, size, direction).await?;
-                    if let Some(handle) = task_handle {
-                        handle.await?;
-                    }
-                    Ok(())
+                Either::Right((Ok(Some(CANCEL_SIGNAL)), _)) => {
+                    info!("Transfer {} cancelled by peer", id);
+                    util::notify_cancelled_by_peer(&self.sender_queue, id).await;
+                    return Ok(());
                 }
-            }
+                // Anything else means the receiver already has the whole payload or gave up
+                Either::Right((signal, streaming)) => {
+                    streaming.await?;
+                    signal
+                }
+            };
+            self.finish_payload(id, confirm, signal).await
         } else {
-            util::notify_rejected(&self.sender_queue).await;
+            info!("Transfer {} rejected: {}", id, rejection);
+            util::notify_rejected(&self.sender_queue, id, rejection).await;
             Ok(())
         }
     }
 
+    /// Reports the outcome once the whole payload is sent. Receivers which `confirm`
+    /// tell whether it matched the digest, the older ones only close the stream when done.
+    async fn finish_payload(
+        &self,
+        id: TransferId,
+        confirm: bool,
+        signal: Result<Option<u8>, io::Error>,
+    ) -> Result<(), io::Error> {
+        match signal {
+            Ok(Some(CANCEL_SIGNAL)) => {
+                info!("Transfer {} cancelled by peer", id);
+                util::notify_cancelled_by_peer(&self.sender_queue, id).await;
+            }
+            Ok(Some(VERIFIED_SIGNAL)) if confirm => {
+                util::notify_completed(&self.sender_queue, id).await;
+            }
+            Ok(Some(CORRUPTED_SIGNAL)) if confirm => {
+                warn!("Transfer {} arrived corrupted", id);
+                util::notify_corrupted(&self.sender_queue, id).await;
+            }
+            Ok(_) if confirm => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "the other device didn't confirm the payload",
+                ));
+            }
+            // Reset stream is closed as well, only the stalled one is a failure
+            Err(e) if confirm || e.kind() == ErrorKind::TimedOut => return Err(e),
+            _ => util::notify_completed(&self.sender_queue, id).await,
+        }
+        Ok(())
+    }
+
+    /// Returns digest, size and number of files of the payload. Hashing a large file takes
+    /// a while, so the progress is reported and the digest is kept for the next attempts.
+    async fn prepare_payload(
+        &self,
+        id: TransferId,
+        algorithm: HashAlgorithm,
+    ) -> Result<(String, usize, usize), io::Error> {
+        let path = match &self.file.payload {
+            Payload::File(path) => path,
+            // Text is small and directories are not hashed as a whole
+            _ => {
+                let (hash, size, files) = self.file.calculate_hash(algorithm).await?;
+                return Ok((hash, size as usize, files));
+            }
+        };
+        let meta = fs::metadata(path).await?;
+        let size = meta.len() as usize;
+        let key = HashCacheKey::new(path, &meta, algorithm);
+        if let Some(hash) = key.as_ref().and_then(|key| self.hashes.get(key)) {
+            info!("Digest of {} is already known", path);
+            return Ok((hash, size, 1));
+        }
+
+        let mut file = fs::File::open(path).await?;
+        let mut hasher = Hasher::new(algorithm);
+        util::notify_preparing(&self.sender_queue, id, 0, size).await;
+        let mut tracker = ProgressTracker::new(0, size);
+        loop {
+            let mut buff = vec![0u8; CHUNK_SIZE];
+            match file.read(&mut buff).await? {
+                0 => break,
+                n => {
+                    hasher.update(&buff[..n]);
+
+                    if let Some(progress) = tracker.advance(n) {
+                        let counter = progress.counter;
+                        util::notify_preparing(&self.sender_queue, id, counter, size).await;
+                    }
+                }
+            }
+        }
+        let hash = hasher.finalize();
+        if let Some(key) = key {
+            self.hashes.insert(key, hash.clone());
+        }
+        Ok((hash, size, 1))
+    }
+
+    async fn stream_payload(
+        &self,
+        socket: impl AsyncWrite + Send + Unpin,
+        id: TransferId,
+        offset: u64,
+        size: usize,
+        files: usize,
+        manifest: Option<HashAlgorithm>,
+    ) -> Result<(), io::Error> {
+        let direction = Direction::Outgoing;
+        let sender_queue = self.sender_queue.clone();
+        let progress = ArchiveProgress::new(sender_queue, id, direction.clone(), size, Some(files));
+        match self
+            .file
+            .get_file_stream(offset, manifest, progress)
+            .await?
+        {
+            StreamOption::File(file) => {
+                let tracker = ProgressTracker::new(offset as usize, size);
+                self.stream_data(socket, file, id, Some(tracker), direction)
+                    .await?;
+            }
+            StreamOption::Zip(file, task_handle) => {
+                // Compressed bytes don't match the size, the archive reports the packed files
+                self.stream_data(socket, file, id, None, direction).await?;
+                if let Some(handle) = task_handle {
+                    handle.await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn stream_data(
         &self,
-        socket: impl TSocketAlias,
+        socket: impl AsyncWrite + Send + Unpin,
         mut file: impl AsyncRead + Unpin,
-        size: usize,
+        id: TransferId,
+        mut tracker: Option<ProgressTracker>,
         direction: Direction,
     ) -> Result<(), io::Error> {
         let mut writer = futio::BufWriter::new(socket);
-        util::notify_progress(&self.sender_queue, 0, size, &direction).await;
-        let mut counter: usize = 0;
-        let mut current_size: usize = 0;
+        if let Some(tracker) = &mut tracker {
+            util::notify_progress(&self.sender_queue, id, tracker.report(), &direction).await;
+        }
         loop {
-            let mut buff = vec![0u8; 1024];
+            let mut buff = vec![0u8; CHUNK_SIZE];
             match file.read(&mut buff).await {
                 Ok(n) if n > 0 => {
                     writer.write_all(&buff[..n]).await?;
-                    counter += buff.len();
-                    current_size += buff.len();
 
-                    if util::time_to_notify(current_size, size) {
-                        util::notify_progress(&self.sender_queue, counter, size, &direction).await;
-                        current_size = 0;
+                    if let Some(progress) = tracker.as_mut().and_then(|t| t.advance(n)) {
+                        util::notify_progress(&self.sender_queue, id, progress, &direction).await;
                     }
                 }
                 Ok(_) => {
                     writer.close().await?;
+                    if let Some(tracker) = &mut tracker {
+                        let progress = tracker.report();
+                        util::notify_progress(&self.sender_queue, id, progress, &direction).await;
+                    }
                     break;
                 }
                 Err(e) => return Err(e),
             }
         }
-        util::notify_completed(&self.sender_queue).await;
         Ok(())
     }
 }
 
 impl UpgradeInfo for TransferOut {
     type Info = &'static str;
-    type InfoIter = iter::Once<Self::Info>;
+    type InfoIter = Vec<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once("/transfer/1.1")
+        PROTOCOL_VERSIONS.to_vec()
     }
 }
 
+// Upgrades only negotiate the protocol, so the substream timeout covers just the negotiation.
+// The transfer itself is driven by the handler and limited by the idle timeout.
 impl<TSocket> InboundUpgrade<TSocket> for TransferPayload
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = TransferPayload;
+    type Output = (TransferPayload, TSocket, Framing);
     type Error = io::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
-
-    fn upgrade_inbound(mut self, socket: TSocket, _: Self::Info) -> Self::Future {
-        Box::pin(async move {
-            info!("Upgrade inbound");
-            let start = Instant::now();
-            self.read_socket(socket).await?;
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
 
-            info!("Finished {:?} ms", start.elapsed().as_millis());
-            Ok(self)
-        })
+    fn upgrade_inbound(self, socket: TSocket, protocol: Self::Info) -> Self::Future {
+        info!("Upgrade inbound: {}", protocol);
+        future::ok((self, socket, Framing::from_protocol(protocol)))
     }
 }
 
@@ -351,20 +1020,13 @@ impl<TSocket> OutboundUpgrade<TSocket> for TransferOut
 where
     TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    type Output = ();
+    type Output = (TransferOut, TSocket, Framing);
     type Error = io::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
 
-    fn upgrade_outbound(self, socket: TSocket, _: Self::Info) -> Self::Future {
-        Box::pin(async move {
-            info!("Upgrade outbound");
-            let start = Instant::now();
-
-            self.write_socket(socket).await?;
-
-            info!("Finished {:?} ms", start.elapsed().as_millis());
-            Ok(())
-        })
+    fn upgrade_outbound(self, socket: TSocket, protocol: Self::Info) -> Self::Future {
+        info!("Upgrade outbound: {}", protocol);
+        future::ok((self, socket, Framing::from_protocol(protocol)))
     }
 }
 
@@ -389,18 +1051,44 @@ impl fmt::Display for ProtocolEvent {
         match self {
             ProtocolEvent::Received(e) => write!(f, "Received {}", e),
             ProtocolEvent::Sent => write!(f, "Sent"),
+            ProtocolEvent::Incompatible => write!(f, "Incompatible"),
         }
     }
 }
 
-impl From<()> for ProtocolEvent {
-    fn from(_: ()) -> Self {
-        ProtocolEvent::Sent
+#[cfg(test)]
+mod tests {
+    use crate::p2p::transfer::protocol::{cancelled_error, deadline, is_cancelled, EofReader};
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, Cursor};
+    use futures::FutureExt;
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn test_is_cancelled() {
+        assert!(is_cancelled(&cancelled_error()));
+        assert!(!is_cancelled(&io::Error::new(
+            io::ErrorKind::Interrupted,
+            "Interrupted"
+        )));
     }
-}
 
-impl From<TransferPayload> for ProtocolEvent {
-    fn from(transfer: TransferPayload) -> Self {
-        ProtocolEvent::Received(transfer)
+    #[test]
+    fn test_eof_reader_closed_stream() {
+        let mut reader = EofReader::new(Cursor::new(vec![1u8, 2, 3]));
+        let mut buff = vec![];
+
+        block_on(reader.read_to_end(&mut buff)).unwrap();
+
+        assert_eq!(buff, vec![1, 2, 3]);
+        assert!(reader.eof);
+    }
+
+    #[test]
+    fn test_deadline() {
+        block_on(deadline(Some(Duration::from_millis(1))));
+
+        assert!(deadline(None).now_or_never().is_none());
     }
 }
@@ -1,7 +1,13 @@
-use std::io::{Error, ErrorKind};
+use std::io::{self, Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use async_std::channel::Sender as AsyncSender;
+use async_std::task;
 use futures::prelude::*;
+use libp2p::core::upgrade::{NegotiationError, UpgradeError};
+use libp2p::swarm::handler::ConnectionHandlerUpgrErr;
 
 #[cfg(unix)]
 use pnet_datalink;
@@ -9,13 +15,98 @@ use pnet_datalink;
 #[cfg(windows)]
 use ipconfig;
 
-use super::peer::{Direction, PeerEvent};
+use super::commands::TransferId;
+use super::peer::{Direction, FileProgress, Peer, PeerEvent, Progress};
+use super::transfer::metadata::Rejection;
 
 // Convenience trait implementation, which helps to alias socket type
 pub trait TSocketAlias: AsyncRead + AsyncWrite + Send + Unpin {}
 impl<T: AsyncRead + AsyncWrite + Send + Unpin> TSocketAlias for T {}
 
 pub const CHUNK_SIZE: usize = 4096;
+/// Progress is reported at most this often, regardless of the speed of the transfer
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// Weight of the newest sample in the smoothed rate
+const RATE_SMOOTHING: f64 = 0.3;
+
+type Timer = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Fails the stream with `TimedOut` when nothing was read or written for the `timeout`.
+/// Unlike the substream timeout, it doesn't limit how long the whole transfer takes.
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    last_progress: Instant,
+    timer: Timer,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            timeout,
+            last_progress: Instant::now(),
+            timer: Box::pin(task::sleep(timeout)),
+        }
+    }
+
+    fn poll_timer(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        // Timer is restarted only when it fires, not on each chunk of data
+        while let Poll::Ready(()) = self.timer.as_mut().poll(cx) {
+            let idle = self.last_progress.elapsed();
+            if idle >= self.timeout {
+                return Err(Error::new(ErrorKind::TimedOut, "Transfer stalled"));
+            }
+            self.timer = Box::pin(task::sleep(self.timeout - idle));
+        }
+        Ok(())
+    }
+
+    fn check<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Ready(result) => {
+                self.last_progress = Instant::now();
+                Poll::Ready(result)
+            }
+            Poll::Pending => match self.poll_timer(cx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.check(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.check(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.check(cx, poll)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_close(cx);
+        self.check(cx, poll)
+    }
+}
 
 pub async fn notify(sender_queue: &AsyncSender<PeerEvent>, event: PeerEvent) {
     if let Err(err) = sender_queue.to_owned().send(event).await {
@@ -23,37 +114,194 @@ pub async fn notify(sender_queue: &AsyncSender<PeerEvent>, event: PeerEvent) {
     }
 }
 
+pub async fn notify_started(
+    sender_queue: &AsyncSender<PeerEvent>,
+    id: TransferId,
+    name: &str,
+    peer: Peer,
+    direction: Direction,
+) {
+    let event = PeerEvent::TransferStarted(id, name.to_string(), peer, direction);
+    notify(sender_queue, event).await;
+}
+
 pub async fn notify_progress(
     sender_queue: &AsyncSender<PeerEvent>,
-    counter: usize,
-    total_size: usize,
+    id: TransferId,
+    progress: Progress,
     direction: &Direction,
 ) {
-    let event = PeerEvent::TransferProgress((counter, total_size, direction.to_owned()));
+    let event = PeerEvent::TransferProgress((id, progress, direction.to_owned()));
     notify(sender_queue, event).await;
 }
 
+pub async fn notify_preparing(
+    sender_queue: &AsyncSender<PeerEvent>,
+    id: TransferId,
+    counter: usize,
+    total_size: usize,
+) {
+    notify(
+        sender_queue,
+        PeerEvent::TransferPreparing((id, counter, total_size)),
+    )
+    .await;
+}
+
 pub async fn notify_error(sender_queue: &AsyncSender<PeerEvent>, error_text: &str) {
     notify(sender_queue, PeerEvent::Error(error_text.to_string())).await;
 }
 
-pub async fn notify_completed(sender_queue: &AsyncSender<PeerEvent>) {
-    notify(sender_queue, PeerEvent::TransferCompleted).await
+pub async fn notify_completed(sender_queue: &AsyncSender<PeerEvent>, id: TransferId) {
+    notify(sender_queue, PeerEvent::TransferCompleted(id)).await
 }
 
-pub async fn notify_waiting(sender_queue: &AsyncSender<PeerEvent>) {
-    notify(sender_queue, PeerEvent::WaitingForAnswer).await
+pub async fn notify_failed(
+    sender_queue: &AsyncSender<PeerEvent>,
+    id: TransferId,
+    error_text: &str,
+) {
+    notify(
+        sender_queue,
+        PeerEvent::TransferFailed(id, error_text.to_string()),
+    )
+    .await
 }
 
-pub async fn notify_rejected(sender_queue: &AsyncSender<PeerEvent>) {
-    notify(sender_queue, PeerEvent::TransferRejected).await
+pub async fn notify_waiting(sender_queue: &AsyncSender<PeerEvent>, id: TransferId) {
+    notify(sender_queue, PeerEvent::WaitingForAnswer(id)).await
 }
 
-pub fn time_to_notify(current_size: usize, total_size: usize) -> bool {
-    if current_size >= ((total_size / 10) + CHUNK_SIZE * 256) {
-        true
-    } else {
-        false
+pub async fn notify_cancelled(sender_queue: &AsyncSender<PeerEvent>, id: TransferId) {
+    notify(sender_queue, PeerEvent::TransferCancelled(id)).await
+}
+
+pub async fn notify_cancelled_by_peer(sender_queue: &AsyncSender<PeerEvent>, id: TransferId) {
+    notify(sender_queue, PeerEvent::TransferCancelledByPeer(id)).await
+}
+
+pub async fn notify_corrupted(sender_queue: &AsyncSender<PeerEvent>, id: TransferId) {
+    notify(sender_queue, PeerEvent::TransferCorrupted(id)).await
+}
+
+pub async fn notify_rejected(
+    sender_queue: &AsyncSender<PeerEvent>,
+    id: TransferId,
+    rejection: Rejection,
+) {
+    notify(sender_queue, PeerEvent::TransferRejected(id, rejection)).await
+}
+
+/// Remote peer doesn't support any of the protocol versions we advertise
+pub fn is_incompatible<E>(error: &ConnectionHandlerUpgrErr<E>) -> bool {
+    matches!(
+        error,
+        ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed))
+    )
+}
+
+/// Counts bytes of the transfer and decides when it's time to report them
+#[derive(Debug)]
+pub struct ProgressTracker {
+    started: Instant,
+    last_report: Instant,
+    last_counter: usize,
+    counter: usize,
+    total: usize,
+    rate: Option<f64>,
+    file: Option<FileProgress>,
+}
+
+impl ProgressTracker {
+    /// Offset is the part done in the previous attempt, it doesn't count into the rate
+    pub fn new(offset: usize, total: usize) -> Self {
+        let now = Instant::now();
+        ProgressTracker {
+            started: now,
+            last_report: now,
+            last_counter: offset,
+            counter: offset,
+            total,
+            rate: None,
+            file: None,
+        }
+    }
+
+    /// Counts the files of the directory archive as well
+    pub fn with_files(mut self, total: Option<usize>) -> Self {
+        self.file = Some(FileProgress {
+            name: String::new(),
+            done: 0,
+            total,
+        });
+        self
+    }
+
+    pub fn start_file(&mut self, name: &str) {
+        if let Some(file) = &mut self.file {
+            file.name = name.to_string();
+        }
+    }
+
+    pub fn finish_file(&mut self) {
+        if let Some(file) = &mut self.file {
+            file.done += 1;
+        }
+    }
+
+    pub fn counter(&self) -> usize {
+        self.counter
+    }
+
+    /// Returns the progress if the last report is old enough
+    pub fn advance(&mut self, bytes: usize) -> Option<Progress> {
+        self.advance_at(bytes, Instant::now())
+    }
+
+    /// Progress at the moment, for the first and the last report
+    pub fn report(&mut self) -> Progress {
+        self.sample(Instant::now())
+    }
+
+    fn advance_at(&mut self, bytes: usize, now: Instant) -> Option<Progress> {
+        self.counter += bytes;
+        if now.duration_since(self.last_report) < PROGRESS_INTERVAL {
+            return None;
+        }
+        Some(self.sample(now))
+    }
+
+    fn sample(&mut self, now: Instant) -> Progress {
+        let since = now.duration_since(self.last_report).as_secs_f64();
+        let transferred = self.counter.saturating_sub(self.last_counter);
+        if since > 0.0 {
+            // Rate is unknown until the first bytes arrive
+            if transferred > 0 || self.rate.is_some() {
+                let current = transferred as f64 / since;
+                self.rate = Some(match self.rate {
+                    Some(rate) => rate + RATE_SMOOTHING * (current - rate),
+                    None => current,
+                });
+            }
+            // Empty files of an archive still move the cadence along
+            self.last_report = now;
+            self.last_counter = self.counter;
+        }
+        let rate = self.rate.unwrap_or(0.0);
+        let remaining = self.total.saturating_sub(self.counter) as f64;
+        let eta = if rate > 0.0 {
+            Some(Duration::from_secs_f64(remaining / rate))
+        } else {
+            None
+        };
+        Progress {
+            counter: self.counter,
+            total: self.total,
+            elapsed: now.duration_since(self.started),
+            rate,
+            eta,
+            file: self.file.clone(),
+        }
     }
 }
 
@@ -111,3 +359,112 @@ pub fn check_network_interfaces() -> Result<(), Error> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::util::{is_incompatible, IdleTimeout, ProgressTracker, PROGRESS_INTERVAL};
+    use async_std::task;
+    use futures::io::{AsyncReadExt, Cursor};
+    use futures::stream::{self, TryStreamExt};
+    use libp2p::core::upgrade::{NegotiationError, UpgradeError};
+    use libp2p::swarm::handler::ConnectionHandlerUpgrErr;
+    use std::io::{self, ErrorKind};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_idle_timeout_stalled_stream() {
+        // Stream which never delivers any data
+        let stalled = stream::pending::<io::Result<Vec<u8>>>().into_async_read();
+        let mut reader = IdleTimeout::new(stalled, Duration::from_millis(50));
+        let mut buff = [0u8; 16];
+
+        let result = task::block_on(reader.read(&mut buff));
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_idle_timeout_active_stream() {
+        let mut reader = IdleTimeout::new(Cursor::new(vec![1u8, 2, 3]), Duration::from_millis(50));
+        let mut buff = vec![];
+
+        task::block_on(reader.read_to_end(&mut buff)).unwrap();
+
+        assert_eq!(buff, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_incompatible_on_failed_negotiation() {
+        let error: ConnectionHandlerUpgrErr<io::Error> =
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed));
+
+        assert!(is_incompatible(&error));
+    }
+
+    #[test]
+    fn test_timeout_is_not_incompatible() {
+        let error: ConnectionHandlerUpgrErr<io::Error> = ConnectionHandlerUpgrErr::Timeout;
+
+        assert!(!is_incompatible(&error));
+    }
+
+    #[test]
+    fn test_progress_cadence() {
+        let mut tracker = ProgressTracker::new(0, 1000);
+        let start = Instant::now();
+
+        assert!(tracker.advance_at(100, start).is_none());
+        let progress = tracker.advance_at(100, start + PROGRESS_INTERVAL).unwrap();
+
+        assert_eq!(progress.counter, 200);
+        assert!(tracker.advance_at(100, start + PROGRESS_INTERVAL).is_none());
+        assert_eq!(tracker.counter(), 300);
+    }
+
+    #[test]
+    fn test_progress_rate_and_eta() {
+        // 100 bytes were there from the previous attempt
+        let mut tracker = ProgressTracker::new(100, 1000);
+        let start = Instant::now();
+
+        let progress = tracker
+            .advance_at(100, start + Duration::from_secs(1))
+            .unwrap();
+        let eta = progress.eta.unwrap().as_secs_f64();
+        assert_eq!(progress.counter, 200);
+        assert!((progress.rate - 100.0).abs() < 1.0);
+        assert!((eta - 8.0).abs() < 0.1);
+
+        // Single faster sample moves the rate only partially
+        let progress = tracker
+            .advance_at(200, start + Duration::from_secs(2))
+            .unwrap();
+        assert!(progress.rate > 110.0 && progress.rate < 190.0);
+        assert!(progress.elapsed >= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_progress_files() {
+        let mut tracker = ProgressTracker::new(0, 1000).with_files(Some(2));
+
+        tracker.start_file("dir/first.txt");
+        tracker.finish_file();
+        tracker.start_file("dir/second.txt");
+        let file = tracker.report().file.unwrap();
+
+        assert_eq!(file.name, "dir/second.txt");
+        assert_eq!(file.done, 1);
+        assert_eq!(file.total, Some(2));
+        assert_eq!(ProgressTracker::new(0, 1000).report().file, None);
+    }
+
+    #[test]
+    fn test_progress_rate_unknown() {
+        let mut tracker = ProgressTracker::new(0, 1000);
+
+        let progress = tracker.report();
+
+        assert_eq!(progress.counter, 0);
+        assert_eq!(progress.eta, None);
+    }
+}
@@ -1,17 +1,29 @@
 use std::fs;
 use std::io::{Error, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use directories_next::{BaseDirs, UserDirs};
+use libp2p::identity::{ed25519, Keypair};
+use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 use toml;
 
 // Unassigned in IANA
 const DEFAULT_LISTEN_PORT: u16 = 36571;
 const DEFAULT_FIREWALL_CHECKED: bool = false;
+const DEFAULT_ACCEPT_POLICY: AcceptPolicy = AcceptPolicy::Reject;
+const DEFAULT_COLLISION_POLICY: CollisionPolicy = CollisionPolicy::Rename;
+/// Seconds to wait for the answer to the incoming transfer before denying it
+const DEFAULT_ANSWER_TIMEOUT: u64 = 120;
+const IDENTITY_FILE: &str = "identity.key";
+const HASH_CACHE_FILE: &str = "hashes.toml";
+const PARTIAL_SUFFIX: &str = ".dragit-part";
+/// Partial files older than that are not worth resuming anymore
+const STALE_PARTIAL_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
 fn generate_full_path(path: &Path, name: &str) -> Result<String, Error> {
-    // If file or dir already exists in the target directory, create a path extended with a timestamp
+    // Existing files and directories are handled by `resolve_collision`
     let joined = path.join(&name);
     joined.into_os_string().into_string().or_else(|_| {
         Err(Error::new(
@@ -21,6 +33,72 @@ fn generate_full_path(path: &Path, name: &str) -> Result<String, Error> {
     })
 }
 
+#[cfg(unix)]
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // Mode is applied only when the file is created, so make sure it's right anyway
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[cfg(not(unix))]
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    // Files in the user profile are accessible only to the user by default
+    let mut file = fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn check_private_file(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        warn!(
+            "{:?} is accessible by other users, fixing permissions",
+            path
+        );
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private_file(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+fn store_new_keypair(path: &Path) -> Result<Keypair, Error> {
+    let keypair = ed25519::Keypair::generate();
+
+    // Write to the temporary file first, so the identity is never left half-written
+    let tmp_path = path.with_extension("tmp");
+    write_private_file(&tmp_path, &keypair.encode())?;
+    fs::rename(&tmp_path, path)?;
+    Ok(Keypair::Ed25519(keypair))
+}
+
+fn load_keypair(path: &Path) -> Result<Keypair, Error> {
+    check_private_file(path)?;
+    let mut bytes = fs::read(path)?;
+    let keypair = ed25519::Keypair::decode(&mut bytes).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid identity file {:?}: {}", path, e),
+        )
+    })?;
+    Ok(Keypair::Ed25519(keypair))
+}
+
 pub fn get_target_path(name: &str, target_path: Option<&String>) -> Result<String, Error> {
     match target_path {
         Some(path) => {
@@ -35,7 +113,159 @@ pub fn get_target_path(name: &str, target_path: Option<&String>) -> Result<Strin
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Partial data of the incoming file. It's named after the hash, so the same
+/// file sent again can continue from where the previous attempt stopped.
+pub fn get_partial_path(hash: &str, target_path: Option<&String>) -> Result<String, Error> {
+    // The hash comes from the remote peer, so it must not be able to point anywhere else
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid hash"));
+    }
+    get_target_path(&format!(".{}{}", hash, PARTIAL_SUFFIX), target_path)
+}
+
+/// Free space on the volume of the downloads directory, None if it can't be checked
+pub fn get_available_space(target_path: Option<&String>) -> Option<u64> {
+    let dir = match target_path {
+        Some(path) => PathBuf::from(path),
+        None => UserConfig::new().ok()?.get_downloads_dir(),
+    };
+    match fs2::available_space(&dir) {
+        Ok(space) => Some(space),
+        Err(e) => {
+            warn!("Could not check free space in {:?}: {:?}", dir, e);
+            None
+        }
+    }
+}
+
+/// Hidden directory, where the incoming directories are unpacked until they are complete
+pub fn create_staging_dir(target_path: Option<&String>) -> Result<String, Error> {
+    let mut number: u32 = 0;
+    loop {
+        let name = format!(".staging-{}{}", number, PARTIAL_SUFFIX);
+        let path = get_target_path(&name, target_path)?;
+        match fs::create_dir(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => number += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn is_stale(meta: &fs::Metadata) -> bool {
+    meta.modified()
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map_or(false, |age| age > STALE_PARTIAL_AGE)
+}
+
+/// Removes the data left by transfers, which didn't finish before the application stopped.
+/// Staging directories can't be resumed, partial files are kept for a while.
+pub fn remove_stale_partials(dir: &Path) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with('.') || !name.ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+        // Doesn't follow symlinks, so nothing outside of the directory is removed
+        let meta = entry.metadata()?;
+        let path = entry.path();
+        let result = if meta.is_dir() {
+            fs::remove_dir_all(&path)
+        } else if is_stale(&meta) {
+            fs::remove_file(&path)
+        } else {
+            continue;
+        };
+        match result {
+            Ok(_) => info!("Removed stale partial data {:?}", path),
+            Err(e) => warn!("Could not remove {:?}: {:?}", path, e),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path which doesn't exist yet, by appending a number to the file stem
+fn generate_unique_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let mut number = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{} ({}){}", stem, number, extension));
+        if candidate.symlink_metadata().is_err() {
+            return candidate;
+        }
+        number += 1;
+    }
+}
+
+/// Path, under which the incoming payload is stored according to the policy.
+/// Returns None if the payload should be skipped. `Ask` has to be answered
+/// by the user before, if it's not, the payload is renamed so nothing is lost.
+pub fn resolve_collision(path: &str, policy: CollisionPolicy) -> Option<String> {
+    let target = Path::new(path);
+    if target.symlink_metadata().is_err() {
+        return Some(path.to_string());
+    }
+    match policy {
+        CollisionPolicy::Overwrite => Some(path.to_string()),
+        CollisionPolicy::Skip => None,
+        CollisionPolicy::Rename | CollisionPolicy::Ask => {
+            let unique = generate_unique_path(target);
+            info!("{} already exists, saving as {:?}", path, unique);
+            Some(unique.to_string_lossy().to_string())
+        }
+    }
+}
+
+pub fn get_collision_policy(policy: Option<CollisionPolicy>) -> Result<CollisionPolicy, Error> {
+    match policy {
+        Some(policy) => Ok(policy),
+        None => Ok(UserConfig::new()?.get_collision_policy()),
+    }
+}
+
+pub fn get_answer_timeout(timeout: Option<Duration>) -> Result<Option<Duration>, Error> {
+    match timeout {
+        Some(timeout) => Ok(Some(timeout)),
+        None => Ok(UserConfig::new()?.get_answer_timeout()),
+    }
+}
+
+/// Decides what happens with the incoming payload, when its name is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+    /// Keep both, the incoming payload gets a number appended to its name
+    Rename,
+    /// Replace the existing file, directories are merged
+    Overwrite,
+    /// Reject the incoming payload
+    Skip,
+    /// Let the user choose one of the above
+    Ask,
+}
+
+/// Decides what the headless daemon does with incoming transfers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceptPolicy {
+    /// Accept every incoming transfer
+    All,
+    /// Accept transfers only from peers listed in `accepted_peers` and paired devices
+    Listed,
+    /// Reject every incoming transfer
+    Reject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Config {
     downloads: String,
 
@@ -44,6 +274,27 @@ struct Config {
 
     #[serde(default = "default_firewall_checked")]
     firewall_checked: bool,
+
+    #[serde(default = "default_accept_policy")]
+    accept_policy: AcceptPolicy,
+
+    #[serde(default)]
+    accepted_peers: Vec<String>,
+
+    /// Daemon rejects payloads bigger than this many bytes, no limit if not set
+    #[serde(default)]
+    accept_max_size: Option<u64>,
+
+    #[serde(default = "default_collision_policy")]
+    collision_policy: CollisionPolicy,
+
+    /// In seconds, 0 waits for the answer forever
+    #[serde(default = "default_answer_timeout")]
+    answer_timeout: u64,
+
+    /// Peer ids of devices paired with this one
+    #[serde(default)]
+    trusted_peers: Vec<String>,
 }
 
 fn default_port() -> u16 {
@@ -54,6 +305,18 @@ fn default_firewall_checked() -> bool {
     DEFAULT_FIREWALL_CHECKED
 }
 
+fn default_accept_policy() -> AcceptPolicy {
+    DEFAULT_ACCEPT_POLICY
+}
+
+fn default_collision_policy() -> CollisionPolicy {
+    DEFAULT_COLLISION_POLICY
+}
+
+fn default_answer_timeout() -> u64 {
+    DEFAULT_ANSWER_TIMEOUT
+}
+
 pub struct UserConfig {
     conf: Config,
     conf_path: PathBuf,
@@ -86,6 +349,12 @@ impl UserConfig {
                 },
                 port: DEFAULT_LISTEN_PORT,
                 firewall_checked: DEFAULT_FIREWALL_CHECKED,
+                accept_policy: DEFAULT_ACCEPT_POLICY,
+                accepted_peers: vec![],
+                accept_max_size: None,
+                collision_policy: DEFAULT_COLLISION_POLICY,
+                answer_timeout: DEFAULT_ANSWER_TIMEOUT,
+                trusted_peers: vec![],
             };
             let toml = Self::serialize_config(config)?;
             let mut file = fs::File::create(&joined_path)?;
@@ -124,29 +393,95 @@ impl UserConfig {
         self.conf.firewall_checked
     }
 
-    pub fn set_downloads_dir(&self, path: &Path) -> Result<(), Error> {
-        // Watch out, this ::create will truncate the file
-        let mut file = fs::File::create(&self.conf_path.as_path())?;
+    pub fn get_accept_policy(&self) -> AcceptPolicy {
+        self.conf.accept_policy
+    }
 
-        let config: Config = Config {
-            downloads: path.to_string_lossy().to_string(),
-            port: self.conf.port,
-            firewall_checked: self.conf.firewall_checked,
-        };
-        let toml = Self::serialize_config(config)?;
-        file.write_all(&toml.as_bytes())?;
-        Ok(())
+    pub fn get_accepted_peers(&self) -> Vec<String> {
+        self.conf.accepted_peers.to_owned()
+    }
+
+    pub fn get_accept_max_size(&self) -> Option<u64> {
+        self.conf.accept_max_size
+    }
+
+    pub fn get_collision_policy(&self) -> CollisionPolicy {
+        self.conf.collision_policy
+    }
+
+    /// None if the answer is awaited forever
+    pub fn get_answer_timeout(&self) -> Option<Duration> {
+        match self.conf.answer_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    fn get_identity_path(&self) -> PathBuf {
+        self.conf_path.with_file_name(IDENTITY_FILE)
+    }
+
+    /// File keeping the digests of the sent files between runs
+    pub fn get_hash_cache_path(&self) -> PathBuf {
+        self.conf_path.with_file_name(HASH_CACHE_FILE)
+    }
+
+    /// Returns the keypair which identifies this device in the network.
+    /// The keypair is generated on first use and stored next to the config file.
+    pub fn get_keypair(&self) -> Result<Keypair, Error> {
+        let path = self.get_identity_path();
+        if path.exists() {
+            load_keypair(&path)
+        } else {
+            info!("Creating new identity in {:?}", path);
+            store_new_keypair(&path)
+        }
+    }
+
+    /// Replaces the stored keypair, so the device shows up with a new peer id.
+    pub fn regenerate_keypair(&self) -> Result<Keypair, Error> {
+        let path = self.get_identity_path();
+        info!("Regenerating identity in {:?}", path);
+        store_new_keypair(&path)
+    }
+
+    pub fn get_trusted_peers(&self) -> Vec<String> {
+        self.conf.trusted_peers.to_owned()
+    }
+
+    pub fn is_trusted(&self, peer_id: &PeerId) -> bool {
+        let peer_id = peer_id.to_base58();
+        self.conf.trusted_peers.iter().any(|p| *p == peer_id)
+    }
+
+    pub fn add_trusted_peer(&mut self, peer_id: &PeerId) -> Result<(), Error> {
+        if !self.is_trusted(peer_id) {
+            self.conf.trusted_peers.push(peer_id.to_base58());
+        }
+        self.write_config(self.conf.clone())
+    }
+
+    pub fn remove_trusted_peer(&mut self, peer_id: &PeerId) -> Result<(), Error> {
+        let peer_id = peer_id.to_base58();
+        self.conf.trusted_peers.retain(|p| *p != peer_id);
+        self.write_config(self.conf.clone())
+    }
+
+    pub fn set_downloads_dir(&self, path: &Path) -> Result<(), Error> {
+        let mut config = self.conf.clone();
+        config.downloads = path.to_string_lossy().to_string();
+        self.write_config(config)
     }
 
     pub fn set_firewall_checked(&self, value: bool) -> Result<(), Error> {
+        let mut config = self.conf.clone();
+        config.firewall_checked = value;
+        self.write_config(config)
+    }
+
+    fn write_config(&self, config: Config) -> Result<(), Error> {
         // Watch out, this ::create will truncate the file
         let mut file = fs::File::create(&self.conf_path.as_path())?;
-
-        let config: Config = Config {
-            downloads: self.conf.downloads.to_owned(),
-            port: self.conf.port,
-            firewall_checked: value,
-        };
         let toml = Self::serialize_config(config)?;
         file.write_all(&toml.as_bytes())?;
         Ok(())
@@ -165,9 +500,14 @@ impl UserConfig {
 
 #[cfg(test)]
 mod tests {
-    use crate::user_data::generate_full_path;
+    use crate::user_data::{
+        create_staging_dir, generate_full_path, get_available_space, get_partial_path,
+        load_keypair, remove_stale_partials, resolve_collision, store_new_keypair, CollisionPolicy,
+    };
+    use libp2p::PeerId;
     use std::fs::{create_dir_all, File};
     use std::path::Path;
+    use std::time::{Duration, SystemTime};
     use tempfile::tempdir;
 
     #[test]
@@ -183,10 +523,110 @@ mod tests {
         let path = dir.path();
         let received_file_name = "a-file.txt";
         File::create(path.join(received_file_name)).unwrap();
+        File::create(path.join("a-file (1).txt")).unwrap();
+
+        let full_path = generate_full_path(path, received_file_name).unwrap();
+        let result = resolve_collision(&full_path, CollisionPolicy::Rename).unwrap();
+
+        assert_eq!(result, path.join("a-file (2).txt").to_string_lossy());
+    }
+
+    #[test]
+    fn test_collision_policies() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a-file.txt");
+        File::create(&path).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let renamed = dir
+            .path()
+            .join("a-file (1).txt")
+            .to_string_lossy()
+            .to_string();
+
+        assert_eq!(
+            resolve_collision(&path, CollisionPolicy::Overwrite),
+            Some(path.clone())
+        );
+        assert_eq!(resolve_collision(&path, CollisionPolicy::Skip), None);
+        assert_eq!(
+            resolve_collision(&path, CollisionPolicy::Ask),
+            Some(renamed)
+        );
+    }
+
+    #[test]
+    fn test_no_collision() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a-file.txt").to_string_lossy().to_string();
+
+        assert_eq!(resolve_collision(&path, CollisionPolicy::Skip), Some(path));
+    }
+
+    #[test]
+    fn test_partial_path() {
+        let target = "/home/user".to_string();
+        let result = get_partial_path("a909b834a8f95194ee2ce975e38cec31", Some(&target)).unwrap();
+
+        assert_eq!(
+            result,
+            "/home/user/.a909b834a8f95194ee2ce975e38cec31.dragit-part"
+        );
+    }
 
-        let result = generate_full_path(path, received_file_name).unwrap();
+    #[test]
+    fn test_partial_path_invalid_hash() {
+        let target = "/home/user".to_string();
+
+        assert!(get_partial_path("../../.bashrc", Some(&target)).is_err());
+        assert!(get_partial_path("", Some(&target)).is_err());
+    }
+
+    #[test]
+    fn test_available_space() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().to_string_lossy().to_string();
+        let missing = dir.path().join("missing").to_string_lossy().to_string();
+
+        assert!(get_available_space(Some(&target)).unwrap() > 0);
+        assert_eq!(get_available_space(Some(&missing)), None);
+    }
+
+    #[test]
+    fn test_staging_dirs_are_unique() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().to_string_lossy().to_string();
+
+        let first = create_staging_dir(Some(&target)).unwrap();
+        let second = create_staging_dir(Some(&target)).unwrap();
 
-        assert_eq!(result, path.join("a-file.txt").to_string_lossy());
+        assert_ne!(first, second);
+        assert!(Path::new(&first).is_dir());
+        assert!(Path::new(&second).is_dir());
+    }
+
+    #[test]
+    fn test_remove_stale_partials() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let staging = path.join(".staging-0.dragit-part");
+        create_dir_all(staging.join("some_directory")).unwrap();
+        let fresh = path.join(".a909b834a8f95194ee2ce975e38cec31.dragit-part");
+        File::create(&fresh).unwrap();
+        let stale = path.join(".8b8de9ed7122ddb61b6481c46c009e75.dragit-part");
+        let month_ago = SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+        File::create(&stale)
+            .unwrap()
+            .set_modified(month_ago)
+            .unwrap();
+        let other = path.join("a-file.txt");
+        File::create(&other).unwrap();
+
+        remove_stale_partials(path).unwrap();
+
+        assert!(!staging.exists());
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(other.exists());
     }
 
     #[test]
@@ -201,8 +641,55 @@ mod tests {
         let path = dir.path();
         let received_dir_name = "some_directory";
         create_dir_all(path.join(received_dir_name)).unwrap();
-        let result = generate_full_path(path, received_dir_name).unwrap();
+        let full_path = generate_full_path(path, received_dir_name).unwrap();
+        let result = resolve_collision(&full_path, CollisionPolicy::Rename).unwrap();
+
+        assert_eq!(result, path.join("some_directory (1)").to_string_lossy());
+    }
+
+    #[test]
+    fn test_keypair_is_persistent() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("identity.key");
+
+        let stored = store_new_keypair(&path).unwrap();
+        let loaded = load_keypair(&path).unwrap();
+
+        assert_eq!(PeerId::from(stored.public()), PeerId::from(loaded.public()));
+    }
+
+    #[test]
+    fn test_keypair_regenerated() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("identity.key");
+
+        let first = store_new_keypair(&path).unwrap();
+        let second = store_new_keypair(&path).unwrap();
+        let loaded = load_keypair(&path).unwrap();
+
+        assert_ne!(PeerId::from(first.public()), PeerId::from(second.public()));
+        assert_eq!(PeerId::from(second.public()), PeerId::from(loaded.public()));
+    }
+
+    #[test]
+    fn test_keypair_invalid_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("identity.key");
+        std::fs::write(&path, b"not a key").unwrap();
+
+        assert!(load_keypair(&path).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_keypair_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("identity.key");
+        store_new_keypair(&path).unwrap();
 
-        assert_eq!(result, path.join("some_directory").to_string_lossy());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
     }
 }
@@ -1,23 +1,31 @@
 use std::io::{Error, Read};
-use std::sync::Arc;
 use std::time::Duration;
 
 use async_std::channel::{bounded, Receiver, Sender};
-use async_std::sync::Mutex;
+use async_std::task;
+use futures::{future, prelude::*};
 use hex;
-use md5::{Digest, Md5};
+use sha2::{Digest, Sha256};
 use tempfile::{tempdir, TempDir};
 
 use libp2p::{
-    core::transport::Transport, core::upgrade, identity, mplex, noise, tcp, PeerId, Swarm,
+    core::transport::Transport,
+    core::upgrade,
+    identity, mplex, noise,
+    swarm::{NetworkBehaviourAction, NotifyHandler, SwarmEvent},
+    tcp, Multiaddr, PeerId, Swarm,
 };
 
 use dragit::p2p::transfer::metadata::HASH_BUFFER_SIZE;
-use dragit::p2p::{FileToSend, PeerEvent, TransferBehaviour, TransferCommand};
+use dragit::p2p::{
+    FileToSend, Payload, PeerEvent, PeerTable, TransferBehaviour, TransferCommand, TransferOut,
+    TransferPayload,
+};
+use dragit::user_data::CollisionPolicy;
 
 #[allow(dead_code)]
 pub fn hash_contents_sync(mut file: impl Read) -> Result<String, Error> {
-    let mut state = Md5::default();
+    let mut state = Sha256::default();
     let mut buffer = [0u8; HASH_BUFFER_SIZE];
 
     loop {
@@ -49,15 +57,17 @@ pub fn build_swarm() -> (
     let local_keys = identity::Keypair::generate_ed25519();
     let local_peer_id = PeerId::from(local_keys.public());
 
-    let command_receiver = Arc::new(Mutex::new(command_receiver));
-
     let dir = tempdir().unwrap();
 
-    let transfer_behaviour = TransferBehaviour::new(
+    let mut transfer_behaviour = TransferBehaviour::new(
         peer_sender.clone(),
         command_receiver,
         Some(dir.path().to_string_lossy().to_string()),
+        PeerTable::default(),
     );
+    // Tests don't depend on the user's config
+    transfer_behaviour.collision_policy = Some(CollisionPolicy::Rename);
+    transfer_behaviour.answer_timeout = Some(Duration::from_secs(60));
 
     let timeout = Duration::from_secs(60);
     let transport = tcp::TcpConfig::new().nodelay(true);
@@ -89,6 +99,88 @@ pub fn build_swarm() -> (
     )
 }
 
+/// Accepts every incoming transfer, like the user clicking "Yes" in the dialog
+pub fn accept_incoming(events: Receiver<PeerEvent>, commands: Sender<TransferCommand>) {
+    task::spawn(async move {
+        while let Ok(event) = events.recv().await {
+            if let PeerEvent::FileIncoming(id, _, _, _, _, _, _, _) = event {
+                commands.send(TransferCommand::Accept(id)).await.unwrap();
+            }
+        }
+    });
+}
+
+/// Connects `sender` to `receiver` listening on the local `port`, sends the payload
+/// and returns it once received. Incoming transfer has to be answered, see `accept_incoming`.
+pub fn run_transfer(
+    mut receiver: Swarm<TransferBehaviour>,
+    mut sender: Swarm<TransferBehaviour>,
+    payload: Payload,
+    port: u16,
+) -> TransferPayload {
+    let (tx, mut rx) = bounded::<Multiaddr>(10);
+    let receiver_id = receiver.local_peer_id().to_owned();
+    let addr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+    Swarm::listen_on(&mut receiver, addr).unwrap();
+
+    let receiving = async move {
+        // Listener is ready once the swarm was polled
+        while receiver.next().now_or_never().is_some() {}
+
+        for addr in receiver.listeners() {
+            tx.send(addr.clone()).await.unwrap();
+        }
+
+        loop {
+            match receiver.next().await.unwrap() {
+                SwarmEvent::ConnectionClosed { cause, .. } => {
+                    panic!("Receiver connection closed: {:?}", cause);
+                }
+                SwarmEvent::Behaviour(event) => return event,
+                _ => (),
+            }
+        }
+    };
+    let sending = async move {
+        let addr = rx.next().await.unwrap();
+        sender.dial(addr).unwrap();
+        let mut payload = Some(payload);
+        loop {
+            match sender.next().await.unwrap() {
+                SwarmEvent::ConnectionEstablished { .. } => {
+                    if let Some(payload) = payload.take() {
+                        let behaviour = sender.behaviour_mut();
+                        let file = FileToSend::new(&receiver_id, payload).unwrap();
+                        let transfer = TransferOut {
+                            file,
+                            sender_queue: behaviour.sender.clone(),
+                            transfers: behaviour.transfers.clone(),
+                            hashes: behaviour.hashes.clone(),
+                            peers: PeerTable::default(),
+                        };
+                        behaviour
+                            .events
+                            .push(NetworkBehaviourAction::NotifyHandler {
+                                handler: NotifyHandler::Any,
+                                peer_id: receiver_id,
+                                event: transfer,
+                            });
+                    }
+                }
+                SwarmEvent::ConnectionClosed { cause, .. } => {
+                    panic!("Sender connection closed: {:?}", cause);
+                }
+                SwarmEvent::Behaviour(event) => return event,
+                _ => (),
+            }
+        }
+    };
+
+    let result = future::select(Box::pin(receiving), Box::pin(sending));
+    let (received, _) = task::block_on(result).factor_first();
+    received
+}
+
 pub fn setup_logger() {
     let env = env_logger::Env::default().filter_or("LOG_LEVEL", "info");
     env_logger::Builder::from_env(env)
@@ -0,0 +1,45 @@
+use std::fs;
+use std::path::Path;
+
+use dragit::p2p::Payload;
+
+mod common;
+
+use common::{accept_incoming, build_swarm, run_transfer, setup_logger};
+
+#[test]
+fn test_batch_transfer() {
+    setup_logger();
+    let (_, sender, events, swarm1, _tempdir1) = build_swarm();
+    let (_, _, _, swarm2, _tempdir2) = build_swarm();
+
+    accept_incoming(events, sender);
+
+    // Files and directories dropped together
+    let payload = Payload::Batch(vec![
+        "tests/data/file.txt".to_string(),
+        "tests/data/test_dir".to_string(),
+        "tests/data/test_dir/test.odt".to_string(),
+    ]);
+    let received = run_transfer(swarm1, swarm2, payload, 3002);
+
+    assert_eq!(received.name, "3 items".to_string());
+
+    match received.payload {
+        Payload::Batch(paths) => {
+            assert_eq!(paths.len(), 3);
+            assert!(paths[0].ends_with("file.txt"));
+            assert!(paths[1].ends_with("test_dir"));
+            assert!(paths[2].ends_with("test.odt"));
+
+            assert!(fs::metadata(&paths[0]).unwrap().is_file());
+            assert!(fs::metadata(&paths[1]).unwrap().is_dir());
+            assert_eq!(fs::metadata(&paths[2]).unwrap().len(), 8988);
+            // Items land next to each other
+            assert_eq!(Path::new(&paths[0]).parent(), Path::new(&paths[2]).parent());
+        }
+        Payload::File(_) => panic!("Got file instead!"),
+        Payload::Dir(_) => panic!("Got directory instead!"),
+        Payload::Text(_) => panic!("Got text instead!"),
+    };
+}
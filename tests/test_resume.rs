@@ -0,0 +1,49 @@
+use std::fs;
+
+use dragit::p2p::{Payload, PeerEvent};
+
+mod common;
+
+use common::{accept_incoming, build_swarm, hash_contents_sync, run_transfer, setup_logger};
+
+#[test]
+fn test_resumed_file_transfer() {
+    setup_logger();
+
+    let file_path = "tests/data/file.txt".to_string();
+    let (_, sender, events, swarm1, tempdir1) = build_swarm();
+    let (_, _, sender_events, swarm2, _tempdir2) = build_swarm();
+
+    // Receiver already has the beginning of the file from the interrupted attempt
+    let contents = fs::read(&file_path).unwrap();
+    let file_hash = hash_contents_sync(&contents[..]).unwrap();
+    let partial_path = tempdir1.path().join(format!(".{}.dragit-part", file_hash));
+    let offset = contents.len() / 2;
+    fs::write(&partial_path, &contents[..offset]).unwrap();
+
+    accept_incoming(events, sender);
+
+    let received = run_transfer(swarm1, swarm2, Payload::File(file_path), 3003);
+
+    assert_eq!(received.name, "file.txt".to_string());
+
+    match received.payload {
+        Payload::File(path) => {
+            assert_eq!(fs::read(path).unwrap(), contents);
+            assert!(!partial_path.exists());
+        }
+        Payload::Dir(_) => panic!("Got directory instead!"),
+        Payload::Text(_) => panic!("Got text instead!"),
+        Payload::Batch(_) => panic!("Got batch instead!"),
+    };
+
+    // Sender starts streaming where the partial file ends
+    let first_progress = std::iter::from_fn(|| sender_events.try_recv().ok())
+        .find_map(|event| match event {
+            PeerEvent::TransferProgress((_, progress, _)) => Some(progress),
+            _ => None,
+        })
+        .expect("Sender didn't report any progress");
+    assert_eq!(first_progress.counter, offset);
+    assert_eq!(first_progress.total, contents.len());
+}